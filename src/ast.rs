@@ -1,3 +1,7 @@
+use crate::host::HostEnv;
+use crate::process_plugin::ProcessHandle;
+use crate::session::Session;
+use crate::signature::Signature;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use tera;
@@ -8,45 +12,489 @@ pub trait Executable: Sync + Send {
         args: &HashMap<String, tera::Value>,
         value: Option<&tera::Value>,
     ) -> tera::Result<tera::Value>;
+
+    /// Like `execute`, with the render context the declaration asked for
+    /// through `context:`. Executables that have no use for it ignore it.
+    fn execute_in_context(
+        &self,
+        args: &HashMap<String, tera::Value>,
+        value: Option<&tera::Value>,
+        context: Option<&tera::Value>,
+    ) -> tera::Result<tera::Value> {
+        let _ = context;
+        self.execute(args, value)
+    }
+}
+
+/// Render state a declaration can receive on every call, listed in its
+/// `context:`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextItem {
+    /// The variables of the render.
+    Vars,
+    /// The template being rendered.
+    TemplateName,
+    /// `--output-file`, null when writing to stdout.
+    OutputPath,
+}
+
+/// Host callbacks a wasm plugin is allowed to use.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HostPermission {
+    /// `host_get_var`: read entries of `vars`.
+    Vars,
+    /// `host_call_function`: call registered functions and filters.
+    Functions,
+    /// `host_render`: render a template string.
+    Render,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Wasm {
     pub path: String,
     pub import: String,
+    pub permissions: Option<Vec<HostPermission>>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+/// The Tera value types a parameter can require.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamType {
+    String,
+    Int,
+    Float,
+    Bool,
+    List,
+    Object,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct Param {
     pub name: String,
     pub description: Option<String>,
-    pub default: Option<String>,
+    /// Accepts any value when unset.
+    #[serde(rename = "type")]
+    pub kind: Option<ParamType>,
+    /// Defaults to `true` unless the parameter has a `default`.
+    pub required: Option<bool>,
+    pub default: Option<tera::Value>,
+    /// The only values the parameter accepts.
+    #[serde(rename = "enum")]
+    pub choices: Option<Vec<tera::Value>>,
+    /// A regular expression the parameter's string form must match.
+    pub pattern: Option<String>,
+    /// Collects every undeclared argument into an object under this name.
+    #[serde(default)]
+    pub variadic: bool,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct FunctionDeclaration {
+/// How declared parameters reach a shell script.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamMode {
+    /// As `JT_PARAM_<name>` environment variables and positional arguments.
+    #[default]
+    Env,
+    /// Pasted into the script text in place of `$(name)`. Values are not
+    /// escaped, so only use this with trusted input.
+    Text,
+}
+
+/// How a plugin's stdout is turned into a Tera value.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// The output string as-is.
+    #[default]
+    Raw,
+    /// The output string without surrounding whitespace.
+    Trim,
+    /// An array with one string per line.
+    Lines,
+    Json,
+    Yaml,
+    Toml,
+    Number,
+    Bool,
+}
+
+/// How the filtered value reaches a shell script.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InputMode {
+    /// As the `JT_INPUT` environment variable.
+    #[default]
+    Env,
+    /// Written to the script's stdin: strings as-is, other values as JSON.
+    Stdin,
+}
+
+/// Limits applied to the child process with `setrlimit` (unix only).
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct ResourceLimits {
+    pub cpu_seconds: Option<u64>,
+    pub address_space_mb: Option<u64>,
+}
+
+/// What a shell plugin call returns.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResultMode {
+    /// The converted stdout.
+    #[default]
+    Output,
+    /// An object with the converted `stdout`, `stderr` and the exit `status`;
+    /// for HTTP plugins, the `status`, `headers` and converted `body`.
+    Full,
+}
+
+/// Where results of a plugin call are memoized.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheMode {
+    /// Every call runs the plugin.
+    #[default]
+    None,
+    /// Identical calls within one run are answered from memory.
+    Render,
+    /// Results are also stored on disk and reused by later runs.
+    Disk,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct CacheOptions {
+    #[serde(default)]
+    pub cache: CacheMode,
+    /// Seconds a `disk` entry stays valid; forever when unset.
+    pub cache_ttl: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InheritMode {
+    All,
+    None,
+}
+
+/// Which variables of our own environment a child process inherits.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Inherit {
+    Mode(InheritMode),
+    /// Only the listed variables.
+    Only(Vec<String>),
+}
+
+/// Controls the environment of child processes. Unset fields fall back to
+/// the plugin file's `env_policy`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct EnvPolicy {
+    /// `all` by default.
+    pub inherit: Option<Inherit>,
+    /// Same as `inherit: none`.
+    pub clear_env: Option<bool>,
+    /// Fixed `PATH`, replacing the inherited one.
+    pub path: Option<String>,
+    /// Variables set from `vars` entries, e.g. `REGISTRY: registry.url`.
+    pub env_from_vars: Option<HashMap<String, String>>,
+}
+
+impl EnvPolicy {
+    /// Fills the fields left unset from `fallback`.
+    pub fn or(&self, fallback: &EnvPolicy) -> EnvPolicy {
+        let env_from_vars = match (&fallback.env_from_vars, &self.env_from_vars) {
+            (Some(fallback), Some(own)) => {
+                let mut merged = fallback.clone();
+                merged.extend(own.clone());
+                Some(merged)
+            }
+            (fallback, own) => own.clone().or_else(|| fallback.clone()),
+        };
+        EnvPolicy {
+            inherit: self.inherit.clone().or_else(|| fallback.inherit.clone()),
+            clear_env: self.clear_env.or(fallback.clear_env),
+            path: self.path.clone().or_else(|| fallback.path.clone()),
+            env_from_vars,
+        }
+    }
+
+    pub fn inherit(&self) -> Inherit {
+        if self.clear_env == Some(true) {
+            return Inherit::Mode(InheritMode::None);
+        }
+        self.inherit
+            .clone()
+            .unwrap_or(Inherit::Mode(InheritMode::All))
+    }
+}
+
+/// How much state one session call can leave behind for the next.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionIsolation {
+    /// Each call runs in a subshell: it sees what `setup` defined, but its
+    /// own changes are discarded.
+    #[default]
+    Subshell,
+    /// Calls run in the session's shell itself, so variables, functions and
+    /// the working directory carry over to later calls.
+    None,
+}
+
+/// The long-lived interpreter of a plugin file, used by declarations with
+/// `session: true`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// A POSIX shell and its arguments.
+    pub interpreter: String,
+    /// Sourced once when the session starts, e.g. to load helper functions.
+    pub setup: Option<String>,
+    pub isolation: SessionIsolation,
+    pub cwd: Option<String>,
+    #[serde(flatten)]
+    pub env_policy: EnvPolicy,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            interpreter: "sh".to_string(),
+            setup: None,
+            isolation: SessionIsolation::default(),
+            cwd: None,
+            env_policy: EnvPolicy::default(),
+        }
+    }
+}
+
+/// A helper program that stays running and answers line-delimited JSON-RPC
+/// requests on stdin/stdout.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ProcessConfig {
+    /// Program and arguments of the helper.
+    pub command: Vec<String>,
+    pub cwd: Option<String>,
+    pub env: Option<HashMap<String, String>>,
+    #[serde(flatten)]
+    pub env_policy: EnvPolicy,
+    /// Name passed to `call`; the declaration name by default.
+    pub method: Option<String>,
+    /// Seconds to wait for each response.
+    pub timeout: Option<f64>,
+    /// Shared by the declarations of a plugin file that run the same helper.
+    #[serde(skip)]
+    pub handle: Option<ProcessHandle>,
+}
+
+/// A request to an HTTP service. `url`, `headers` and `body` are Tera
+/// templates rendered with the arguments in scope.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HttpConfig {
+    #[serde(default = "HttpConfig::default_method")]
+    pub method: String,
+    /// `http://host[:port]/path`, or just the path with `unix_socket`.
+    pub url: String,
+    pub headers: Option<HashMap<String, String>>,
+    pub body: Option<String>,
+    /// Path of a unix domain socket to send the request to.
+    pub unix_socket: Option<String>,
+    /// Seconds one attempt may take, 30 by default.
+    pub timeout: Option<f64>,
+    /// Further attempts after a connection error, a timeout or a 429 or 5xx
+    /// response. Only GET, HEAD, OPTIONS and TRACE requests are retried
+    /// unless `retry_unsafe` is set.
+    #[serde(default)]
+    pub retries: u32,
+    /// Also retries requests that can change the service's state, such as
+    /// POST, PUT or PATCH.
+    #[serde(default)]
+    pub retry_unsafe: bool,
+    /// Seconds before the first retry, doubled for each further one.
+    pub retry_delay: Option<f64>,
+}
+
+impl HttpConfig {
+    fn default_method() -> String {
+        "GET".to_string()
+    }
+}
+
+/// An embedded Rhai script, run without file or process access.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RhaiOptions {
+    pub rhai: Option<String>,
+    /// Path of a file holding the script, instead of an inline `rhai`.
+    pub rhai_file: Option<String>,
+    /// Operations one call may run before it is stopped.
+    pub max_operations: Option<u64>,
+}
+
+/// Settings of the shell backend, shared by every declaration kind.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ShellOptions {
+    /// Program and arguments run directly, without a shell, instead of `script`.
+    /// `$(name)` placeholders are replaced within each argument.
+    pub exec: Option<Vec<String>>,
+    /// Program and arguments running the script, e.g. `bash -euo pipefail`.
+    pub interpreter: Option<String>,
+    /// Path of a file holding the script, instead of an inline `script`.
+    pub script_file: Option<String>,
+    /// Working directory of the command.
+    pub cwd: Option<String>,
+    #[serde(default)]
+    pub param_mode: ParamMode,
+    #[serde(default)]
+    pub output: OutputFormat,
+    #[serde(default)]
+    pub input: InputMode,
+    #[serde(default)]
+    pub result: ResultMode,
+    /// Exit codes treated as success, `[0]` by default.
+    pub ok_codes: Option<Vec<i32>>,
+    /// Returns the output of commands that fail instead of an error.
+    #[serde(default)]
+    pub allow_failure: bool,
+    /// Forwards the command's stderr to the log.
+    #[serde(default)]
+    pub log_stderr: bool,
+    /// Runs the script in the plugin file's session instead of a new process.
+    #[serde(default)]
+    pub session: bool,
+    #[serde(skip)]
+    pub session_handle: Option<Session>,
+    #[serde(flatten)]
+    pub env_policy: EnvPolicy,
+    /// Seconds after which the command and its children are killed.
+    pub timeout: Option<f64>,
+    /// Maximum number of bytes read from stdout or stderr.
+    pub max_output: Option<usize>,
+    #[serde(default)]
+    pub limits: ResourceLimits,
+}
+
+/// A function, filter or tester of a plugin file, which runs on exactly one
+/// backend. Which of the three it is follows from the section listing it.
+#[derive(Debug, Default, Deserialize)]
+pub struct Declaration {
     pub name: String,
     pub params: Option<Vec<Param>>,
     pub env: Option<HashMap<String, String>>,
     pub description: Option<String>,
+    /// Render state passed along with the arguments.
+    #[serde(default)]
+    pub context: Vec<ContextItem>,
     pub wasm: Option<Wasm>,
+    /// A Tera template rendered with the arguments in scope.
+    pub template: Option<String>,
+    pub http: Option<HttpConfig>,
+    pub process: Option<ProcessConfig>,
     pub script: Option<String>,
+    #[serde(flatten)]
+    pub shell: ShellOptions,
+    #[serde(flatten)]
+    pub rhai: RhaiOptions,
+    #[serde(flatten)]
+    pub caching: CacheOptions,
+}
+
+/// Macro libraries and partials a plugin file registers on the template
+/// environment.
+#[derive(Debug, Default, Deserialize)]
+pub struct TemplatesSection {
+    /// Registered names are `<prefix>.<name>`, like `--include-dir` aliases.
+    pub prefix: Option<String>,
+    pub files: Vec<TemplateFile>,
+}
+
+/// One template of `templates:`, inline or read from a file.
+#[derive(Debug, Default, Deserialize)]
+pub struct TemplateFile {
+    /// Defaults to the file name of `path`.
+    pub name: Option<String>,
+    pub body: Option<String>,
+    /// Relative to the directory of the plugin file.
+    pub path: Option<String>,
+}
+
+/// When a `globals:` entry runs.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GlobalStage {
+    /// Before the variables file, which can use the value.
+    #[default]
+    BeforeVariables,
+    /// After the variables file, whose values the provider sees in `vars`.
+    AfterVariables,
+}
+
+/// A value computed once per render by any plugin backend and added to `vars`.
+#[derive(Debug, Default, Deserialize)]
+pub struct GlobalDeclaration {
+    /// Called without arguments; its `name` is the key set in `vars`.
+    #[serde(flatten)]
+    pub provider: Declaration,
+    /// Deep-merges the returned object into `vars` instead.
+    #[serde(default)]
+    pub merge: bool,
+    #[serde(default)]
+    pub stage: GlobalStage,
 }
 
+/// Registers every export a wasm module describes in its metadata.
 #[derive(Debug, Deserialize)]
-pub struct FilterDeclaration {
+pub struct WasmModuleDeclaration {
+    pub wasm_module: String,
+    /// Prepended to each export name when it is registered.
+    pub prefix: Option<String>,
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+    pub permissions: Option<Vec<HostPermission>>,
+}
+
+/// What a declaration or module export is registered as.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportKind {
+    Function,
+    Filter,
+    Tester,
+}
+
+impl ExportKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ExportKind::Function => "function",
+            ExportKind::Filter => "filter",
+            ExportKind::Tester => "tester",
+        }
+    }
+}
+
+/// One entry of the document returned by a module's metadata export.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ExportMetadata {
     pub name: String,
-    pub params: Option<Vec<Param>>,
-    pub env: Option<HashMap<String, String>>,
+    pub kind: ExportKind,
     pub description: Option<String>,
-    pub wasm: Option<Wasm>,
-    pub script: Option<String>,
+    pub params: Option<Vec<Param>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ModuleMetadata {
+    pub exports: Vec<ExportMetadata>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct WasmDeclartion {
     pub wasm: Wasm,
     pub params: Option<Vec<Param>>,
+    #[serde(default)]
+    pub name: String,
+    #[serde(skip)]
+    pub host: HostEnv,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -59,7 +507,13 @@ pub struct WasmFilter {
     pub decl: WasmDeclartion,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WasmTester {
+    pub decl: WasmDeclartion,
+}
+
 pub struct ExecutableFunction {
     pub executor: Box<dyn Executable>,
     pub name: String,
+    pub signature: Signature,
 }
@@ -0,0 +1,356 @@
+// src/cache.rs
+use crate::ast::{CacheMode, CacheOptions, EnvPolicy, Executable};
+use crate::command::inherited_env;
+use crate::host::HostEnv;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tera::Value;
+
+/// Results of plugin calls, kept in memory for the current render and
+/// optionally on disk across runs.
+#[derive(Clone, Debug)]
+pub struct Cache {
+    inner: Arc<CacheState>,
+}
+
+#[derive(Debug)]
+struct CacheState {
+    enabled: bool,
+    /// Where `disk` entries are stored; without it they are kept in memory.
+    dir: Option<PathBuf>,
+    memory: Mutex<HashMap<String, Value>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DiskEntry {
+    /// Seconds since the unix epoch.
+    created: u64,
+    value: Value,
+}
+
+/// A cache that never touches the disk.
+impl Default for Cache {
+    fn default() -> Self {
+        Cache {
+            inner: Arc::new(CacheState {
+                enabled: true,
+                dir: None,
+                memory: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Sorts object keys recursively so that equal values hash equally.
+pub fn canonical(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (k.clone(), canonical(v)))
+                    .collect(),
+            )
+        }
+        Value::Array(arr) => Value::Array(arr.iter().map(canonical).collect()),
+        other => other.clone(),
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl Cache {
+    /// Stores `disk` entries under `dir`. `enabled: false` turns every
+    /// cached plugin back into a plain call.
+    pub fn new(dir: PathBuf, enabled: bool) -> Self {
+        Cache {
+            inner: Arc::new(CacheState {
+                enabled,
+                dir: Some(dir),
+                memory: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// `$JINTEMPLIFY_CACHE_DIR`, or `jintemplify` under the user's cache
+    /// directory.
+    pub fn default_dir() -> PathBuf {
+        if let Some(dir) = std::env::var_os("JINTEMPLIFY_CACHE_DIR") {
+            return PathBuf::from(dir);
+        }
+        let base = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .unwrap_or_else(std::env::temp_dir);
+        base.join("jintemplify")
+    }
+
+    /// Forgets every cached result, in memory and on disk.
+    pub fn clear(&self) -> std::io::Result<()> {
+        self.inner.memory.lock().unwrap().clear();
+        let Some(dir) = &self.inner.dir else {
+            return Ok(());
+        };
+        match fs::remove_dir_all(dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Where the `disk` entry `key` is stored, if the cache has a directory.
+    fn entry_path(&self, key: &str, options: &CacheOptions) -> Option<PathBuf> {
+        if options.cache != CacheMode::Disk {
+            return None;
+        }
+        let dir = self.inner.dir.as_ref()?;
+        Some(dir.join(format!("{}.json", key)))
+    }
+
+    fn get(&self, key: &str, options: &CacheOptions) -> Option<Value> {
+        if let Some(value) = self.inner.memory.lock().unwrap().get(key) {
+            return Some(value.clone());
+        }
+        let content = fs::read(self.entry_path(key, options)?).ok()?;
+        let entry: DiskEntry = serde_json::from_slice(&content).ok()?;
+        if let Some(ttl) = options.cache_ttl {
+            if now().saturating_sub(entry.created) > ttl {
+                debug!("cache entry {} expired", key);
+                return None;
+            }
+        }
+        self.inner
+            .memory
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), entry.value.clone());
+        Some(entry.value)
+    }
+
+    fn put(&self, key: &str, options: &CacheOptions, value: &Value) {
+        self.inner
+            .memory
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), value.clone());
+        let Some(path) = self.entry_path(key, options) else {
+            return;
+        };
+        let entry = DiskEntry {
+            created: now(),
+            value: value.clone(),
+        };
+        let written = fs::create_dir_all(path.parent().unwrap_or(Path::new(""))).and_then(|_| {
+            let content = serde_json::to_vec(&entry)?;
+            fs::write(&path, content)
+        });
+        if let Err(e) = written {
+            warn!("Failed to write cache entry {}: {}", key, e);
+        }
+    }
+}
+
+/// Memoizes another executor. `fingerprint` identifies the plugin's code, so
+/// that editing a script or rebuilding a wasm module invalidates old entries.
+pub struct CachedExecutable {
+    pub executor: Box<dyn Executable>,
+    pub cache: Cache,
+    pub options: CacheOptions,
+    pub fingerprint: String,
+    /// Environment policy of a backend running a child process: the
+    /// environment it resolves to is part of the key.
+    pub env_policy: Option<EnvPolicy>,
+    /// Resolves `env_from_vars`.
+    pub host: HostEnv,
+}
+
+impl CachedExecutable {
+    fn key(
+        &self,
+        args: &HashMap<String, Value>,
+        value: Option<&Value>,
+        context: Option<&Value>,
+    ) -> tera::Result<String> {
+        let args = canonical(&serde_json::to_value(args)?);
+        let value = value.map(canonical);
+        let context = context.map(canonical);
+        let key =
+            serde_json::to_vec(&(&self.fingerprint, args, value, context, self.environment()))?;
+        Ok(sha256_hex(&key))
+    }
+
+    /// The `env_from_vars` values and inherited variables the child process
+    /// would see.
+    fn environment(&self) -> Option<Value> {
+        let policy = self.env_policy.as_ref()?;
+        let from_vars: BTreeMap<_, _> = policy
+            .env_from_vars
+            .iter()
+            .flatten()
+            .map(|(name, path)| (name, self.host.get_var(path)))
+            .collect();
+        Some(serde_json::json!({
+            "env_from_vars": from_vars,
+            "inherited": inherited_env(policy),
+        }))
+    }
+}
+
+impl Executable for CachedExecutable {
+    fn execute(&self, args: &HashMap<String, Value>, value: Option<&Value>) -> tera::Result<Value> {
+        self.execute_in_context(args, value, None)
+    }
+
+    fn execute_in_context(
+        &self,
+        args: &HashMap<String, Value>,
+        value: Option<&Value>,
+        context: Option<&Value>,
+    ) -> tera::Result<Value> {
+        if !self.cache.inner.enabled || self.options.cache == CacheMode::None {
+            return self.executor.execute_in_context(args, value, context);
+        }
+        let key = self.key(args, value, context)?;
+        if let Some(cached) = self.cache.get(&key, &self.options) {
+            debug!("cache hit: {}", key);
+            return Ok(cached);
+        }
+        let result = self.executor.execute_in_context(args, value, context)?;
+        self.cache.put(&key, &self.options, &result);
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Inherit;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Counter(Arc<AtomicUsize>);
+
+    impl Executable for Counter {
+        fn execute(
+            &self,
+            _args: &HashMap<String, Value>,
+            _value: Option<&Value>,
+        ) -> tera::Result<Value> {
+            Ok(Value::from(self.0.fetch_add(1, Ordering::SeqCst)))
+        }
+    }
+
+    fn cached(cache: &Cache, calls: &Arc<AtomicUsize>, mode: CacheMode) -> CachedExecutable {
+        CachedExecutable {
+            executor: Box::new(Counter(calls.clone())),
+            cache: cache.clone(),
+            options: CacheOptions {
+                cache: mode,
+                cache_ttl: None,
+            },
+            fingerprint: "counter".to_string(),
+            env_policy: None,
+            host: HostEnv::default(),
+        }
+    }
+
+    #[test]
+    fn test_cache_modes() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("cache");
+        let calls = Arc::new(AtomicUsize::new(0));
+        let args: HashMap<String, Value> =
+            serde_json::from_str(r#"{"a": 1, "b": {"y": 2, "x": 1}}"#).unwrap();
+        let reordered: HashMap<String, Value> =
+            serde_json::from_str(r#"{"b": {"x": 1, "y": 2}, "a": 1}"#).unwrap();
+
+        let cache = Cache::new(dir.clone(), true);
+        let render = cached(&cache, &calls, CacheMode::Render);
+        assert_eq!(render.execute(&args, None).unwrap(), Value::from(0));
+        assert_eq!(render.execute(&reordered, None).unwrap(), Value::from(0));
+        assert_eq!(
+            render.execute(&args, Some(&Value::from("x"))).unwrap(),
+            Value::from(1)
+        );
+        assert!(!dir.exists());
+
+        let disk = cached(&cache, &calls, CacheMode::Disk);
+        assert_eq!(disk.execute(&HashMap::new(), None).unwrap(), Value::from(2));
+        // A new process only sees the entry written to disk.
+        let disk = cached(&Cache::new(dir.clone(), true), &calls, CacheMode::Disk);
+        assert_eq!(disk.execute(&HashMap::new(), None).unwrap(), Value::from(2));
+
+        let none = cached(&cache, &calls, CacheMode::None);
+        assert_eq!(none.execute(&args, None).unwrap(), Value::from(3));
+        let disabled = cached(&Cache::new(dir.clone(), false), &calls, CacheMode::Disk);
+        assert_eq!(
+            disabled.execute(&HashMap::new(), None).unwrap(),
+            Value::from(4)
+        );
+
+        cache.clear().unwrap();
+        assert!(!dir.exists());
+        let disk = cached(&cache, &calls, CacheMode::Disk);
+        assert_eq!(disk.execute(&HashMap::new(), None).unwrap(), Value::from(5));
+    }
+
+    #[test]
+    fn test_environment_is_part_of_the_key() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = Cache::default();
+        let host = HostEnv::default();
+        host.set_vars(&serde_json::json!({"registry": "a"}))
+            .unwrap();
+        let policy: EnvPolicy =
+            serde_yaml::from_str("{inherit: none, env_from_vars: {REGISTRY: registry}}").unwrap();
+        let executable = |policy: &EnvPolicy| CachedExecutable {
+            env_policy: Some(policy.clone()),
+            host: host.clone(),
+            ..cached(&cache, &calls, CacheMode::Disk)
+        };
+
+        let args = HashMap::new();
+        assert_eq!(
+            executable(&policy).execute(&args, None).unwrap(),
+            Value::from(0)
+        );
+        assert_eq!(
+            executable(&policy).execute(&args, None).unwrap(),
+            Value::from(0)
+        );
+        host.set_vars(&serde_json::json!({"registry": "b"}))
+            .unwrap();
+        assert_eq!(
+            executable(&policy).execute(&args, None).unwrap(),
+            Value::from(1)
+        );
+        let inherit_path = EnvPolicy {
+            inherit: Some(Inherit::Only(vec!["PATH".to_string()])),
+            ..policy.clone()
+        };
+        assert_eq!(
+            executable(&inherit_path).execute(&args, None).unwrap(),
+            Value::from(2)
+        );
+        // The default cache keeps `disk` entries in memory.
+        assert!(cache.inner.dir.is_none());
+    }
+}
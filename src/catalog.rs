@@ -0,0 +1,739 @@
+// src/catalog.rs
+use crate::ast::{Declaration, ExportKind, Param, ParamType};
+use crate::plugin::Plugin;
+use log::warn;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+/// How `plugins list` and `plugins describe` print the catalog.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CatalogFormat {
+    Text,
+    Markdown,
+    Json,
+}
+
+/// A function, filter or tester available to templates.
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    pub name: String,
+    pub kind: ExportKind,
+    /// `tera`, `builtin` or the path of the plugin file.
+    pub source: String,
+    /// How a plugin is implemented: `script`, `exec`, `wasm`, `template`,
+    /// `http`, `process` or `rhai`.
+    pub backend: Option<String>,
+    pub description: Option<String>,
+    pub params: Vec<Param>,
+}
+
+struct Builtin {
+    name: &'static str,
+    description: &'static str,
+    /// Names and descriptions of the arguments.
+    params: &'static [(&'static str, &'static str)],
+}
+
+const fn builtin(
+    name: &'static str,
+    description: &'static str,
+    params: &'static [(&'static str, &'static str)],
+) -> Builtin {
+    Builtin {
+        name,
+        description,
+        params,
+    }
+}
+
+const BUILTIN_FUNCTIONS: &[Builtin] = &[
+    builtin(
+        "read_file",
+        "Returns the content of a file.",
+        &[("file_path", "Path of the file")],
+    ),
+    builtin(
+        "shell",
+        "Runs a shell command and returns its output. Other string arguments are passed as environment variables.",
+        &[
+            ("cmd", "The command"),
+            ("interpreter", "Program running the command, `sh` by default"),
+            ("timeout", "Seconds after which the command is killed"),
+            ("max_output", "Maximum number of bytes of output"),
+            ("cpu_seconds", "CPU time limit"),
+            ("address_space_mb", "Memory limit"),
+        ],
+    ),
+];
+
+const BUILTIN_FILTERS: &[Builtin] = &[
+    builtin(
+        "to_object",
+        "Converts a list of key/value pairs to an object.",
+        &[],
+    ),
+    builtin("from_json", "Parses a JSON string.", &[]),
+    builtin("from_yaml", "Parses a YAML string.", &[]),
+    builtin("from_toml", "Parses a TOML string.", &[]),
+];
+
+// The built-ins of Tera below are listed from Tera 1.20; `test_tera_builtins`
+// checks that each of them is still registered by `Tera::default()`.
+const TERA_FUNCTIONS: &[Builtin] = &[
+    builtin(
+        "range",
+        "Returns a list of numbers.",
+        &[("start", ""), ("end", ""), ("step_by", "")],
+    ),
+    builtin(
+        "now",
+        "Returns the current date and time.",
+        &[("timestamp", ""), ("utc", "")],
+    ),
+    builtin("throw", "Fails the render.", &[("message", "")]),
+    builtin(
+        "get_random",
+        "Returns a random integer.",
+        &[("start", ""), ("end", "")],
+    ),
+    builtin(
+        "get_env",
+        "Returns an environment variable.",
+        &[("name", ""), ("default", "")],
+    ),
+];
+
+const TERA_FILTERS: &[Builtin] = &[
+    builtin("upper", "Uppercases a string.", &[]),
+    builtin("lower", "Lowercases a string.", &[]),
+    builtin("trim", "Removes surrounding whitespace.", &[]),
+    builtin("trim_start", "Removes leading whitespace.", &[]),
+    builtin("trim_end", "Removes trailing whitespace.", &[]),
+    builtin(
+        "trim_start_matches",
+        "Removes a leading pattern.",
+        &[("pat", "")],
+    ),
+    builtin(
+        "trim_end_matches",
+        "Removes a trailing pattern.",
+        &[("pat", "")],
+    ),
+    builtin(
+        "truncate",
+        "Shortens a string.",
+        &[("length", ""), ("end", "")],
+    ),
+    builtin("wordcount", "Counts the words of a string.", &[]),
+    builtin(
+        "replace",
+        "Replaces a substring.",
+        &[("from", ""), ("to", "")],
+    ),
+    builtin("capitalize", "Capitalizes the first character.", &[]),
+    builtin("title", "Capitalizes every word.", &[]),
+    builtin("linebreaksbr", "Replaces line breaks with `<br>`.", &[]),
+    builtin(
+        "indent",
+        "Indents every line.",
+        &[("prefix", ""), ("first", ""), ("blank", "")],
+    ),
+    builtin("striptags", "Removes HTML tags.", &[]),
+    builtin("spaceless", "Removes whitespace between HTML tags.", &[]),
+    builtin("urlencode", "Percent-encodes a string for a URL path.", &[]),
+    builtin(
+        "urlencode_strict",
+        "Percent-encodes every special character.",
+        &[],
+    ),
+    builtin("escape", "Escapes HTML.", &[]),
+    builtin("escape_xml", "Escapes XML.", &[]),
+    builtin("slugify", "Turns a string into a slug.", &[]),
+    builtin("addslashes", "Escapes quotes with backslashes.", &[]),
+    builtin("split", "Splits a string into a list.", &[("pat", "")]),
+    builtin(
+        "int",
+        "Converts to an integer.",
+        &[("default", ""), ("base", "")],
+    ),
+    builtin("float", "Converts to a float.", &[("default", "")]),
+    builtin("first", "Returns the first element.", &[]),
+    builtin("last", "Returns the last element.", &[]),
+    builtin("nth", "Returns the nth element.", &[("n", "")]),
+    builtin("join", "Joins a list into a string.", &[("sep", "")]),
+    builtin("sort", "Sorts a list.", &[("attribute", "")]),
+    builtin(
+        "unique",
+        "Removes duplicates.",
+        &[("attribute", ""), ("case_sensitive", "")],
+    ),
+    builtin(
+        "slice",
+        "Returns part of a list.",
+        &[("start", ""), ("end", "")],
+    ),
+    builtin(
+        "group_by",
+        "Groups a list of objects.",
+        &[("attribute", "")],
+    ),
+    builtin(
+        "filter",
+        "Keeps objects whose attribute has a value.",
+        &[("attribute", ""), ("value", "")],
+    ),
+    builtin(
+        "map",
+        "Takes one attribute of each object.",
+        &[("attribute", "")],
+    ),
+    builtin("concat", "Appends to a list.", &[("with", "")]),
+    builtin("abs", "Absolute value.", &[]),
+    builtin(
+        "pluralize",
+        "Returns a plural suffix.",
+        &[("singular", ""), ("plural", "")],
+    ),
+    builtin(
+        "round",
+        "Rounds a number.",
+        &[("method", ""), ("precision", "")],
+    ),
+    builtin(
+        "filesizeformat",
+        "Formats a number of bytes.",
+        &[("binary", "")],
+    ),
+    builtin("length", "Length of a string, list or object.", &[]),
+    builtin("reverse", "Reverses a string or list.", &[]),
+    builtin(
+        "date",
+        "Formats a timestamp or date.",
+        &[("format", ""), ("timezone", "")],
+    ),
+    builtin("json_encode", "Serializes to JSON.", &[("pretty", "")]),
+    builtin("as_str", "Converts to a string.", &[]),
+    builtin(
+        "truncate",
+        "Shortens a string to a number of characters.",
+        &[("length", ""), ("end", "")],
+    ),
+    builtin("wordcount", "Counts the words of a string.", &[]),
+    builtin(
+        "replace",
+        "Replaces a substring.",
+        &[("from", ""), ("to", "")],
+    ),
+    builtin("capitalize", "Uppercases the first character.", &[]),
+    builtin("title", "Uppercases the first character of each word.", &[]),
+    builtin("linebreaksbr", "Replaces line breaks with `<br>`.", &[]),
+    builtin(
+        "indent",
+        "Indents each line.",
+        &[("prefix", ""), ("first", ""), ("blank", "")],
+    ),
+    builtin("striptags", "Removes HTML tags.", &[]),
+    builtin("spaceless", "Removes whitespace between HTML tags.", &[]),
+    builtin("urlencode", "Percent-encodes a string, keeping `/`.", &[]),
+    builtin("urlencode_strict", "Percent-encodes a string.", &[]),
+    builtin("escape", "Escapes HTML.", &[]),
+    builtin("escape_xml", "Escapes XML.", &[]),
+    builtin("slugify", "Turns a string into a slug.", &[]),
+    builtin("addslashes", "Escapes quotes with backslashes.", &[]),
+    builtin("split", "Splits a string into a list.", &[("pat", "")]),
+    builtin(
+        "int",
+        "Converts a value to an integer.",
+        &[("default", ""), ("base", "")],
+    ),
+    builtin("float", "Converts a value to a float.", &[("default", "")]),
+    builtin("first", "Returns the first element of a list.", &[]),
+    builtin("last", "Returns the last element of a list.", &[]),
+    builtin("nth", "Returns an element of a list.", &[("n", "")]),
+    builtin("join", "Joins a list into a string.", &[("sep", "")]),
+    builtin("sort", "Sorts a list.", &[("attribute", "")]),
+    builtin(
+        "unique",
+        "Removes duplicates from a list.",
+        &[("attribute", ""), ("case_sensitive", "")],
+    ),
+    builtin(
+        "slice",
+        "Returns part of a list.",
+        &[("start", ""), ("end", "")],
+    ),
+    builtin(
+        "group_by",
+        "Groups a list of objects by an attribute.",
+        &[("attribute", "")],
+    ),
+    builtin(
+        "filter",
+        "Keeps the objects of a list whose attribute has a value.",
+        &[("attribute", ""), ("value", "")],
+    ),
+    builtin(
+        "map",
+        "Returns an attribute of each object of a list.",
+        &[("attribute", "")],
+    ),
+    builtin("concat", "Appends values to a list.", &[("with", "")]),
+    builtin("abs", "Returns the absolute value of a number.", &[]),
+    builtin(
+        "pluralize",
+        "Returns a plural suffix for a number.",
+        &[("singular", ""), ("plural", "")],
+    ),
+    builtin(
+        "round",
+        "Rounds a number.",
+        &[("method", ""), ("precision", "")],
+    ),
+    builtin("filesizeformat", "Formats a number of bytes.", &[]),
+    builtin(
+        "length",
+        "Returns the length of a string, list or object.",
+        &[],
+    ),
+    builtin("reverse", "Reverses a string or list.", &[]),
+    builtin(
+        "date",
+        "Formats a timestamp or date.",
+        &[("format", ""), ("timezone", "")],
+    ),
+    builtin(
+        "json_encode",
+        "Serializes a value to JSON.",
+        &[("pretty", "")],
+    ),
+    builtin("as_str", "Converts a value to a string.", &[]),
+    builtin(
+        "get",
+        "Returns a key of an object.",
+        &[("key", ""), ("default", "")],
+    ),
+];
+
+const TERA_TESTERS: &[Builtin] = &[
+    builtin("defined", "Whether the value is defined.", &[]),
+    builtin("undefined", "Whether the value is undefined.", &[]),
+    builtin("odd", "Whether a number is odd.", &[]),
+    builtin("even", "Whether a number is even.", &[]),
+    builtin("string", "Whether the value is a string.", &[]),
+    builtin("number", "Whether the value is a number.", &[]),
+    builtin(
+        "divisibleby",
+        "Whether a number is divisible by another.",
+        &[("divisor", "")],
+    ),
+    builtin("iterable", "Whether the value is a list or object.", &[]),
+    builtin("object", "Whether the value is an object.", &[]),
+    builtin(
+        "starting_with",
+        "Whether a string starts with another.",
+        &[("prefix", "")],
+    ),
+    builtin(
+        "ending_with",
+        "Whether a string ends with another.",
+        &[("suffix", "")],
+    ),
+    builtin(
+        "containing",
+        "Whether a string, list or object contains a value.",
+        &[("value", "")],
+    ),
+    builtin(
+        "matching",
+        "Whether a string matches a regex.",
+        &[("regex", "")],
+    ),
+];
+
+fn builtin_entries(builtins: &[Builtin], kind: ExportKind, source: &str) -> Vec<Entry> {
+    builtins
+        .iter()
+        .map(|builtin| Entry {
+            name: builtin.name.to_string(),
+            kind,
+            source: source.to_string(),
+            backend: None,
+            description: Some(builtin.description.to_string()),
+            params: builtin
+                .params
+                .iter()
+                .map(|(name, description)| Param {
+                    name: name.to_string(),
+                    description: (!description.is_empty()).then(|| description.to_string()),
+                    ..Default::default()
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+impl Declaration {
+    fn entry(&self, kind: ExportKind, source: &str) -> Entry {
+        let backends = self.backends();
+        Entry {
+            name: self.name.clone(),
+            kind,
+            source: source.to_string(),
+            backend: (!backends.is_empty()).then(|| backends.join(", ")),
+            description: self.description.clone(),
+            params: self.params.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// The functions, filters and testers declared by `plugin`.
+fn declared<'a>(plugin: &'a Plugin, source: &'a str) -> impl Iterator<Item = Entry> + 'a {
+    plugin
+        .declarations()
+        .map(move |(kind, decl)| decl.entry(kind, source))
+}
+
+/// Everything registered for a render: Tera's built-ins, ours unless
+/// disabled, and the declarations of `plugin`, which was loaded from `source`.
+pub fn entries(plugin: Option<(&Plugin, &str)>, builtin_functions: bool) -> Vec<Entry> {
+    let mut entries = builtin_entries(TERA_FUNCTIONS, ExportKind::Function, "tera");
+    if builtin_functions {
+        entries.extend(builtin_entries(
+            BUILTIN_FUNCTIONS,
+            ExportKind::Function,
+            "builtin",
+        ));
+    }
+    entries.extend(builtin_entries(TERA_FILTERS, ExportKind::Filter, "tera"));
+    entries.extend(builtin_entries(
+        BUILTIN_FILTERS,
+        ExportKind::Filter,
+        "builtin",
+    ));
+    entries.extend(builtin_entries(TERA_TESTERS, ExportKind::Tester, "tera"));
+    if let Some((plugin, source)) = plugin {
+        entries.extend(declared(plugin, source));
+    }
+    entries
+}
+
+/// Fails when a declaration of `plugin` would replace one of Tera's or
+/// jintemplify's built-ins, or another declaration of the same kind in the
+/// same file or in another imported file. With `allow_override`, the
+/// collisions are only logged.
+pub fn check_collisions(
+    plugin: &Plugin,
+    source: &str,
+    builtin_functions: bool,
+    allow_override: bool,
+) -> anyhow::Result<()> {
+    let mut registered: HashMap<(&str, String), String> = entries(None, builtin_functions)
+        .into_iter()
+        .map(|entry| ((entry.kind.name(), entry.name), entry.source))
+        .collect();
+    let mut collisions = plugin.collisions.clone();
+    for entry in declared(plugin, source) {
+        let kind = entry.kind.name();
+        let Some(previous) = registered.insert((kind, entry.name.clone()), entry.source) else {
+            continue;
+        };
+        collisions.push(match previous.as_str() {
+            "tera" => format!("{} '{}' replaces Tera's {}", kind, entry.name, kind),
+            "builtin" => format!("{} '{}' replaces the builtin {}", kind, entry.name, kind),
+            _ => format!("{} '{}' is declared more than once", kind, entry.name),
+        });
+    }
+    if collisions.is_empty() {
+        return Ok(());
+    }
+    if allow_override {
+        for collision in collisions.iter() {
+            warn!("{}: {}", source, collision);
+        }
+        return Ok(());
+    }
+    anyhow::bail!(
+        "{}: {} (use a namespace, or --allow-override to replace them)",
+        source,
+        collisions.join("; ")
+    )
+}
+
+/// `string, required` or `int, default: 3`, for one parameter.
+fn param_summary(param: &Param) -> String {
+    let mut parts = Vec::new();
+    if let Some(kind) = param.kind {
+        parts.push(
+            match kind {
+                ParamType::String => "string",
+                ParamType::Int => "int",
+                ParamType::Float => "float",
+                ParamType::Bool => "bool",
+                ParamType::List => "list",
+                ParamType::Object => "object",
+            }
+            .to_string(),
+        );
+    }
+    if param.variadic {
+        parts.push("variadic".to_string());
+    } else if let Some(default) = &param.default {
+        parts.push(format!("default: {}", default));
+    } else if param.required == Some(true) {
+        parts.push("required".to_string());
+    } else if param.required == Some(false) {
+        parts.push("optional".to_string());
+    }
+    if let Some(choices) = &param.choices {
+        let choices: Vec<_> = choices.iter().map(|c| c.to_string()).collect();
+        parts.push(format!("one of {}", choices.join(", ")));
+    }
+    if let Some(pattern) = &param.pattern {
+        parts.push(format!("matching {}", pattern));
+    }
+    parts.join(", ")
+}
+
+fn text_list(entries: &[Entry]) -> String {
+    let width = |f: fn(&Entry) -> usize| entries.iter().map(f).max().unwrap_or(0);
+    let name_width = width(|e| e.name.len()).max(4);
+    let source_width = width(|e| e.backend.as_deref().unwrap_or(&e.source).len()).max(6);
+    let mut out = String::new();
+    for entry in entries {
+        let _ = writeln!(
+            out,
+            "{:<8}  {:<name_width$}  {:<source_width$}  {}",
+            entry.kind.name(),
+            entry.name,
+            entry.backend.as_deref().unwrap_or(&entry.source),
+            entry.description.as_deref().unwrap_or_default()
+        );
+    }
+    out
+}
+
+fn text_entry(entry: &Entry) -> String {
+    let mut out = format!(
+        "{} ({}, {}{})\n",
+        entry.name,
+        entry.kind.name(),
+        entry.source,
+        entry
+            .backend
+            .as_ref()
+            .map(|backend| format!(", {}", backend))
+            .unwrap_or_default()
+    );
+    if let Some(description) = &entry.description {
+        let _ = writeln!(out, "  {}", description.trim());
+    }
+    if !entry.params.is_empty() {
+        out.push_str("\nParameters:\n");
+        let width = entry.params.iter().map(|p| p.name.len()).max().unwrap_or(0);
+        for param in entry.params.iter() {
+            let summary = param_summary(param);
+            let description = param.description.as_deref().unwrap_or_default();
+            let details = [summary.as_str(), description]
+                .into_iter()
+                .filter(|s| !s.is_empty())
+                .collect::<Vec<_>>()
+                .join(" - ");
+            let _ = writeln!(out, "  {:<width$}  {}", param.name, details);
+        }
+    }
+    out
+}
+
+fn markdown_cell(text: &str) -> String {
+    text.trim().replace('|', "\\|").replace('\n', " ")
+}
+
+fn markdown_entry(entry: &Entry) -> String {
+    let mut out = format!("### `{}`\n\n", entry.name);
+    let _ = writeln!(
+        out,
+        "*{}* from `{}`{}\n",
+        entry.kind.name(),
+        entry.source,
+        entry
+            .backend
+            .as_ref()
+            .map(|backend| format!(" ({})", backend))
+            .unwrap_or_default()
+    );
+    if let Some(description) = &entry.description {
+        let _ = writeln!(out, "{}\n", description.trim());
+    }
+    if !entry.params.is_empty() {
+        out.push_str("| Parameter | Details | Description |\n|---|---|---|\n");
+        for param in entry.params.iter() {
+            let _ = writeln!(
+                out,
+                "| `{}` | {} | {} |",
+                param.name,
+                markdown_cell(&param_summary(param)),
+                markdown_cell(param.description.as_deref().unwrap_or_default())
+            );
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn markdown_list(entries: &[Entry]) -> String {
+    let mut out = String::from("# Template reference\n\n");
+    for (kind, title) in [
+        (ExportKind::Function, "Functions"),
+        (ExportKind::Filter, "Filters"),
+        (ExportKind::Tester, "Testers"),
+    ] {
+        let _ = writeln!(out, "## {}\n", title);
+        for entry in entries.iter().filter(|entry| entry.kind == kind) {
+            out.push_str(&markdown_entry(entry));
+        }
+    }
+    out
+}
+
+/// Renders every entry, or with `name` only those of that name.
+pub fn render(
+    entries: &[Entry],
+    name: Option<&str>,
+    format: CatalogFormat,
+) -> anyhow::Result<String> {
+    let Some(name) = name else {
+        return Ok(match format {
+            CatalogFormat::Text => text_list(entries),
+            CatalogFormat::Markdown => markdown_list(entries),
+            CatalogFormat::Json => serde_json::to_string_pretty(entries)? + "\n",
+        });
+    };
+    let matches: Vec<&Entry> = entries.iter().filter(|e| e.name == name).collect();
+    if matches.is_empty() {
+        anyhow::bail!("No function, filter or tester named '{}'", name);
+    }
+    Ok(match format {
+        CatalogFormat::Text => matches
+            .into_iter()
+            .map(text_entry)
+            .collect::<Vec<_>>()
+            .join("\n"),
+        CatalogFormat::Markdown => matches.into_iter().map(markdown_entry).collect(),
+        CatalogFormat::Json => serde_json::to_string_pretty(&matches)? + "\n",
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catalog() {
+        let plugin: Plugin = serde_yaml::from_str(
+            r#"
+functions:
+  - name: greet
+    description: Says hello.
+    params:
+      - name: who
+        type: string
+        description: Who to greet
+      - name: times
+        type: int
+        default: 1
+    script: echo hello "$1"
+filters:
+  - name: shout
+    template: "{{ input | upper }}"
+"#,
+        )
+        .unwrap();
+        let entries = entries(Some((&plugin, "plugin.yaml")), true);
+        assert!(entries.iter().any(|e| e.name == "read_file"));
+        assert!(entries.iter().any(|e| e.name == "matching"));
+        assert!(!super::entries(None, false)
+            .iter()
+            .any(|e| e.name == "shell"));
+
+        let text = render(&entries, Some("greet"), CatalogFormat::Text).unwrap();
+        assert_eq!(
+            text,
+            "greet (function, plugin.yaml, script)\n  Says hello.\n\nParameters:\n  who    string - Who to greet\n  times  int, default: 1\n"
+        );
+        let list = render(&entries, None, CatalogFormat::Text).unwrap();
+        assert!(list.contains("\nfilter    shout "), "{}", list);
+
+        let markdown = render(&entries, None, CatalogFormat::Markdown).unwrap();
+        assert!(markdown.contains("### `greet`\n\n*function* from `plugin.yaml` (script)\n\nSays hello.\n\n| Parameter | Details | Description |\n|---|---|---|\n| `who` | string | Who to greet |\n"));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&render(&entries, Some("shout"), CatalogFormat::Json).unwrap())
+                .unwrap();
+        assert_eq!(json[0]["backend"], "template");
+        assert_eq!(json[0]["kind"], "filter");
+
+        assert!(render(&entries, Some("nope"), CatalogFormat::Text).is_err());
+    }
+
+    #[test]
+    fn test_tera_builtins() {
+        let tera = tera::Tera::default();
+        for builtin in TERA_FUNCTIONS {
+            assert!(tera.get_function(builtin.name).is_ok(), "{}", builtin.name);
+        }
+        for builtin in TERA_FILTERS {
+            assert!(tera.get_filter(builtin.name).is_ok(), "{}", builtin.name);
+        }
+        for builtin in TERA_TESTERS {
+            assert!(tera.get_tester(builtin.name).is_ok(), "{}", builtin.name);
+        }
+    }
+
+    #[test]
+    fn test_collisions() {
+        let plugin: Plugin = serde_yaml::from_str(
+            r#"
+functions:
+  - name: read_file
+    script: cat "$1"
+  - name: twice
+    script: echo 1
+  - name: twice
+    script: echo 2
+filters:
+  - name: upper
+    script: echo "$JT_INPUT"
+  - name: read_file
+    script: echo "$JT_INPUT"
+"#,
+        )
+        .unwrap();
+        let err = check_collisions(&plugin, "plugin.yaml", true, false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "plugin.yaml: function 'read_file' replaces the builtin function; \
+             function 'twice' is declared more than once; \
+             filter 'upper' replaces Tera's filter \
+             (use a namespace, or --allow-override to replace them)"
+        );
+        check_collisions(&plugin, "plugin.yaml", true, true).unwrap();
+
+        let plugin: Plugin =
+            serde_yaml::from_str("functions:\n  - name: read_file\n    script: cat \"$1\"\n")
+                .unwrap();
+        check_collisions(&plugin, "plugin.yaml", false, false).unwrap();
+
+        let mut plugin = Plugin::default();
+        plugin
+            .collisions
+            .push("function 'greet' is declared by both a.yaml and b.yaml".to_string());
+        let err = check_collisions(&plugin, "plugin.yaml", true, false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "plugin.yaml: function 'greet' is declared by both a.yaml and b.yaml \
+             (use a namespace, or --allow-override to replace them)"
+        );
+        check_collisions(&plugin, "plugin.yaml", true, true).unwrap();
+    }
+}
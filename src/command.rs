@@ -1,7 +1,16 @@
-use crate::ast::Param;
-use log::debug;
-use std::collections::HashMap;
-use std::process::Command;
+use crate::ast::{
+    EnvPolicy, Inherit, InheritMode, OutputFormat, Param, ResourceLimits, ShellOptions,
+};
+use log::{debug, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::io::{Read, Write};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tempfile::TempPath;
 
 pub fn tera_to_str(value: &tera::Value) -> tera::Result<String> {
     match value {
@@ -14,22 +23,59 @@ pub fn tera_to_str(value: &tera::Value) -> tera::Result<String> {
     }
 }
 
+/// Converts the stdout of plugin `name` according to `format`.
+pub fn parse_output(name: &str, format: OutputFormat, output: String) -> tera::Result<tera::Value> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        tera::Error::msg(format!(
+            "Plugin '{}': output is not valid {}: {}",
+            name,
+            format!("{:?}", format).to_lowercase(),
+            e
+        ))
+    };
+    match format {
+        OutputFormat::Raw => Ok(tera::Value::String(output)),
+        OutputFormat::Trim => Ok(tera::Value::String(output.trim().to_string())),
+        OutputFormat::Lines => Ok(tera::Value::Array(
+            output
+                .lines()
+                .map(|line| tera::Value::String(line.to_string()))
+                .collect(),
+        )),
+        OutputFormat::Json => serde_json::from_str(&output).map_err(|e| invalid(&e)),
+        OutputFormat::Yaml => serde_yaml::from_str(&output).map_err(|e| invalid(&e)),
+        OutputFormat::Toml => toml::from_str(&output).map_err(|e| invalid(&e)),
+        OutputFormat::Number => {
+            let text = output.trim();
+            if let Ok(n) = text.parse::<i64>() {
+                Ok(tera::Value::from(n))
+            } else {
+                text.parse::<f64>()
+                    .ok()
+                    .and_then(serde_json::Number::from_f64)
+                    .map(tera::Value::Number)
+                    .ok_or_else(|| invalid(&format!("'{}'", text)))
+            }
+        }
+        OutputFormat::Bool => match output.trim().to_lowercase().as_str() {
+            "true" | "yes" | "1" => Ok(tera::Value::Bool(true)),
+            "false" | "no" | "0" => Ok(tera::Value::Bool(false)),
+            text => Err(invalid(&format!("'{}'", text))),
+        },
+    }
+}
+
 #[allow(dead_code)]
 pub fn replace_placeholder(
     cmd: &mut String,
     param: &Param,
     args: &HashMap<String, tera::Value>,
 ) -> tera::Result<()> {
-    if !args.contains_key(&param.name) && param.default.is_none() {
-        return Err(tera::Error::msg(format!(
-            "Parameter '{}' not provided and no default value is set.",
-            param.name
-        )));
-    }
     let placeholder = format!("$({})", param.name);
-    let value = args.get(&param.name).unwrap();
-
-    let value_str = tera_to_str(value)?;
+    let value_str = match args.get(&param.name).or(param.default.as_ref()) {
+        Some(value) => tera_to_str(value)?,
+        None => String::new(),
+    };
     debug!("placeholder: {} => {}", placeholder, value_str);
     *cmd = cmd.replace(&placeholder, &value_str);
     Ok(())
@@ -39,56 +85,438 @@ pub fn execute_shell_command(
     cmd: &str,
     env: &Option<HashMap<String, String>>,
     interpreter: Option<&str>,
+    options: &RunOptions,
 ) -> tera::Result<String> {
     debug!(
         "interpreter: {:?}, shell command: {}, env: {:?}",
         interpreter, cmd, env
     );
-    let mut shell_cmd = if let Some(interpreter) = interpreter {
-        run_with_interpreter(interpreter, cmd, env.as_ref())
-    } else {
-        run_with_shebang(cmd, env.as_ref())
+    let shell_cmd = run_with_interpreter(interpreter.unwrap_or("sh"), cmd, env.as_ref())
+        .map_err(|e| tera::Error::msg(format!("Failed to execute command '{}': {}", cmd, e)))?;
+    run_command(shell_cmd, cmd, options)
+}
+
+/// Writes `script` to an executable temporary file and builds the command
+/// running it: through `interpreter` (a program and its arguments, such as
+/// `bash -euo pipefail`) when given, directly when the script starts with a
+/// shebang line, and through `sh` otherwise. `args` follow the script file.
+/// The file is removed when the returned `TempPath` is dropped.
+pub fn script_command(
+    script: &str,
+    env: Option<&HashMap<String, String>>,
+    interpreter: Option<&str>,
+    args: &[String],
+) -> Result<(Command, TempPath), std::io::Error> {
+    let mut file = tempfile::Builder::new().prefix("jintemplify-").tempfile()?;
+    file.write_all(script.as_bytes())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.as_file()
+            .set_permissions(std::fs::Permissions::from_mode(0o700))?;
     }
-    .map_err(|e| tera::Error::msg(format!("Failed to execute command '{}': {}", cmd, e)))?;
+    // Closing the file before running it avoids ETXTBSY.
+    let path = file.into_temp_path();
 
-    let output = shell_cmd.output();
+    let mut command = match interpreter {
+        Some(interpreter) => {
+            let mut parts = interpreter.split_whitespace();
+            let program = parts.next().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "interpreter must not be empty",
+                )
+            })?;
+            let mut command = Command::new(program);
+            command.args(parts).arg(&path);
+            command
+        }
+        None if script.starts_with("#!") => Command::new(&path),
+        None => {
+            let mut command = Command::new("sh");
+            command.arg(&path);
+            command
+        }
+    };
+    command.args(args);
+    if let Some(envs) = env {
+        command.envs(envs);
+    }
+    debug!("script command: {:?}", command);
+    Ok((command, path))
+}
 
-    match &output {
-        Ok(o) if o.status.success() => {
-            let output_str = String::from_utf8_lossy(&o.stdout).into_owned();
-            debug!("shell command: {} => output_str: {}", cmd, output_str);
-            Ok(output_str)
+/// Builds a command running `argv` directly, without any shell in between.
+pub fn exec_command(
+    argv: &[String],
+    env: Option<&HashMap<String, String>>,
+) -> Result<Command, std::io::Error> {
+    let (program, args) = argv.split_first().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "exec must not be empty")
+    })?;
+    let mut command = Command::new(program);
+    command.args(args);
+    if let Some(envs) = env {
+        command.envs(envs);
+    }
+    Ok(command)
+}
+
+/// How a prepared child process is run.
+#[derive(Debug, Default)]
+pub struct RunOptions {
+    /// Names the command in timeout and output limit errors.
+    pub name: String,
+    /// Written to the child's stdin, which is closed afterwards.
+    pub stdin: Option<Vec<u8>>,
+    pub timeout: Option<Duration>,
+    /// Maximum number of bytes read from stdout or stderr.
+    pub max_output: Option<usize>,
+    pub limits: ResourceLimits,
+    /// Forwards the child's stderr to the log.
+    pub log_stderr: bool,
+    pub env_policy: EnvPolicy,
+}
+
+impl RunOptions {
+    /// Reads the `timeout`, `max_output` and `limits` settings of `options`.
+    pub fn from_shell_options(name: &str, options: &ShellOptions) -> tera::Result<RunOptions> {
+        let timeout = options
+            .timeout
+            .map(|secs| {
+                Duration::try_from_secs_f64(secs).map_err(|e| {
+                    tera::Error::msg(format!(
+                        "Plugin '{}': invalid timeout {}: {}",
+                        name, secs, e
+                    ))
+                })
+            })
+            .transpose()?;
+        Ok(RunOptions {
+            name: name.to_string(),
+            timeout,
+            max_output: options.max_output,
+            limits: options.limits,
+            log_stderr: options.log_stderr,
+            env_policy: options.env_policy.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+pub enum RunError {
+    Io(std::io::Error),
+    TimedOut(Duration),
+    OutputTooLarge(usize),
+}
+
+impl From<std::io::Error> for RunError {
+    fn from(e: std::io::Error) -> Self {
+        RunError::Io(e)
+    }
+}
+
+impl RunError {
+    pub fn into_tera(self, display: &str, options: &RunOptions) -> tera::Error {
+        match self {
+            RunError::Io(e) => {
+                tera::Error::msg(format!("Failed to execute command '{}': {}", display, e))
+            }
+            RunError::TimedOut(elapsed) => tera::Error::msg(format!(
+                "'{}' timed out after {:.1?}: {}",
+                options.name, elapsed, display
+            )),
+            RunError::OutputTooLarge(max) => tera::Error::msg(format!(
+                "'{}' produced more than {} bytes of output: {}",
+                options.name, max, display
+            )),
         }
-        Ok(o) => Err(tera::Error::msg(format!(
-            "Failed to execute command '{}': {}",
-            cmd,
-            String::from_utf8_lossy(&o.stderr)
-        ))),
-        Err(e) => Err(tera::Error::msg(format!(
+    }
+}
+
+/// Puts the child in its own process group, so that everything it starts can
+/// be killed together, and applies `limits` to it.
+#[cfg(unix)]
+pub fn isolate(command: &mut Command, limits: ResourceLimits) {
+    use std::os::unix::process::CommandExt;
+
+    command.process_group(0);
+    if limits.cpu_seconds.is_none() && limits.address_space_mb.is_none() {
+        return;
+    }
+    let rlimit = |value: u64| libc::rlimit {
+        rlim_cur: value as libc::rlim_t,
+        rlim_max: value as libc::rlim_t,
+    };
+    // SAFETY: the closure only calls `setrlimit`, which is async-signal-safe.
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(cpu) = limits.cpu_seconds {
+                if libc::setrlimit(libc::RLIMIT_CPU, &rlimit(cpu)) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            if let Some(mb) = limits.address_space_mb {
+                if libc::setrlimit(libc::RLIMIT_AS, &rlimit(mb.saturating_mul(1024 * 1024))) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub fn isolate(_command: &mut Command, limits: ResourceLimits) {
+    if limits.cpu_seconds.is_some() || limits.address_space_mb.is_some() {
+        log::warn!("resource limits are only supported on unix, ignoring them");
+    }
+}
+
+pub fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    {
+        // SAFETY: plain syscall; the child leads its own process group.
+        unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = child.kill();
+    }
+    let _ = child.wait();
+}
+
+/// Reads `pipe` to the end on another thread, stopping once more than `max`
+/// bytes were read and raising `exceeded`.
+fn read_pipe<R: Read + Send + 'static>(
+    pipe: Option<R>,
+    max: Option<usize>,
+    exceeded: Arc<AtomicBool>,
+) -> JoinHandle<std::io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let Some(mut pipe) = pipe else {
+            return Ok(buf);
+        };
+        match max {
+            Some(max) => {
+                (&mut pipe).take(max as u64 + 1).read_to_end(&mut buf)?;
+                if buf.len() > max {
+                    exceeded.store(true, Ordering::SeqCst);
+                }
+            }
+            None => {
+                pipe.read_to_end(&mut buf)?;
+            }
+        }
+        Ok(buf)
+    })
+}
+
+fn join_pipe(handle: JoinHandle<std::io::Result<Vec<u8>>>) -> Result<Vec<u8>, RunError> {
+    handle
+        .join()
+        .map_err(|_| std::io::Error::other("output reader panicked"))?
+        .map_err(RunError::Io)
+}
+
+/// The variables of our own environment a child inherits under `policy`.
+pub fn inherited_env(policy: &EnvPolicy) -> BTreeMap<String, String> {
+    let lossy = |(name, value): (OsString, OsString)| {
+        (
+            name.to_string_lossy().into_owned(),
+            value.to_string_lossy().into_owned(),
+        )
+    };
+    match policy.inherit() {
+        Inherit::Mode(InheritMode::All) => std::env::vars_os().map(lossy).collect(),
+        Inherit::Mode(InheritMode::None) => BTreeMap::new(),
+        Inherit::Only(names) => names
+            .iter()
+            .filter_map(|name| std::env::var_os(name).map(|value| (name.into(), value)))
+            .map(lossy)
+            .collect(),
+    }
+}
+
+/// Rebuilds the environment of `command` according to `policy`, keeping the
+/// variables set explicitly on `command`.
+pub fn apply_env_policy(command: &mut Command, policy: &EnvPolicy) {
+    let inherit = policy.inherit();
+    if inherit != Inherit::Mode(InheritMode::All) {
+        let explicit: Vec<(OsString, OsString)> = command
+            .get_envs()
+            .filter_map(|(key, value)| value.map(|value| (key.to_owned(), value.to_owned())))
+            .collect();
+        command.env_clear();
+        if let Inherit::Only(names) = &inherit {
+            for name in names {
+                if let Some(value) = std::env::var_os(name) {
+                    command.env(name, value);
+                }
+            }
+        }
+        command.envs(explicit);
+    }
+    if let Some(path) = &policy.path {
+        command.env("PATH", path);
+    }
+}
+
+/// Spawns `command`, retrying while another thread that forked concurrently
+/// still holds a freshly written script file open.
+pub fn spawn(command: &mut Command) -> std::io::Result<Child> {
+    let mut attempts = 0;
+    loop {
+        match command.spawn() {
+            #[cfg(unix)]
+            Err(e) if e.raw_os_error() == Some(libc::ETXTBSY) && attempts < 20 => {
+                attempts += 1;
+                thread::sleep(Duration::from_millis(5));
+            }
+            result => return result,
+        }
+    }
+}
+
+fn output_with_options(mut command: Command, options: &RunOptions) -> Result<Output, RunError> {
+    command
+        .stdin(match options.stdin {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    apply_env_policy(&mut command, &options.env_policy);
+    isolate(&mut command, options.limits);
+
+    let started = Instant::now();
+    let mut child = spawn(&mut command)?;
+    // Feed stdin from another thread so a child that writes before it has read
+    // everything cannot deadlock against us.
+    let writer = child
+        .stdin
+        .take()
+        .zip(options.stdin.clone())
+        .map(|(mut pipe, stdin)| thread::spawn(move || pipe.write_all(&stdin)));
+    let exceeded = Arc::new(AtomicBool::new(false));
+    let stdout = read_pipe(child.stdout.take(), options.max_output, exceeded.clone());
+    let stderr = read_pipe(child.stderr.take(), options.max_output, exceeded.clone());
+
+    // Processes the child left running in the background can hold the pipes
+    // open after it exited, so the limits apply until both readers are done.
+    let mut poll = Duration::from_millis(1);
+    let mut exited = None;
+    let status = loop {
+        if exited.is_none() {
+            exited = child.try_wait()?;
+        }
+        if let Some(status) = exited {
+            if stdout.is_finished() && stderr.is_finished() {
+                break status;
+            }
+        }
+        if exceeded.load(Ordering::SeqCst) {
+            kill(&mut child);
+            return Err(RunError::OutputTooLarge(
+                options.max_output.unwrap_or_default(),
+            ));
+        }
+        if let Some(timeout) = options.timeout {
+            if started.elapsed() >= timeout {
+                kill(&mut child);
+                return Err(RunError::TimedOut(started.elapsed()));
+            }
+        }
+        thread::sleep(poll);
+        poll = (poll * 2).min(Duration::from_millis(50));
+    };
+
+    let output = Output {
+        status,
+        stdout: join_pipe(stdout)?,
+        stderr: join_pipe(stderr)?,
+    };
+    if exceeded.load(Ordering::SeqCst) {
+        return Err(RunError::OutputTooLarge(
+            options.max_output.unwrap_or_default(),
+        ));
+    }
+    if let Some(writer) = writer {
+        match writer.join() {
+            Ok(Err(e)) if e.kind() != std::io::ErrorKind::BrokenPipe => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(output)
+}
+
+/// What a finished command wrote and how it exited.
+#[derive(Debug)]
+pub struct CommandOutput {
+    pub stdout: String,
+    pub stderr: String,
+    /// Exit code, `None` when the command was killed by a signal.
+    pub status: Option<i32>,
+}
+
+impl CommandOutput {
+    /// The error reported for a command that exited unsuccessfully: its
+    /// stderr, or its exit status when it wrote nothing there.
+    pub fn failure(&self, display: &str) -> tera::Error {
+        tera::Error::msg(format!(
             "Failed to execute command '{}': {}",
-            cmd, e
-        ))),
+            display,
+            if self.stderr.is_empty() {
+                match self.status {
+                    Some(code) => format!("exit status: {}", code),
+                    None => "killed by a signal".to_string(),
+                }
+            } else {
+                self.stderr.clone()
+            }
+        ))
     }
 }
 
-fn run_with_shebang(
-    cmd: &str,
-    env_vars: Option<&HashMap<String, String>>,
-) -> Result<Command, std::io::Error> {
-    let (interpreter, actual_cmd) = extract_interpreter(cmd);
-    let mut command = run_with_interpreter(&interpreter, &actual_cmd, env_vars)?;
-    debug!("run with shebang: {}, cmd={}", interpreter, actual_cmd);
+/// Runs `command` to completion, whatever its exit status.
+pub fn run_captured(
+    command: Command,
+    display: &str,
+    options: &RunOptions,
+) -> tera::Result<CommandOutput> {
+    let output =
+        output_with_options(command, options).map_err(|e| e.into_tera(display, options))?;
+    let output = CommandOutput {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        status: output.status.code(),
+    };
+    debug!(
+        "shell command: {} => status: {:?}, output_str: {}",
+        display, output.status, output.stdout
+    );
+    log_stderr(&output, options);
+    Ok(output)
+}
 
-    command.spawn()?.wait()?;
-    Ok(command)
+/// Forwards `output.stderr` to the log when `options.log_stderr` is set.
+pub fn log_stderr(output: &CommandOutput, options: &RunOptions) {
+    if options.log_stderr {
+        for line in output.stderr.lines() {
+            warn!("[{}] {}", options.name, line);
+        }
+    }
 }
 
-pub fn extract_interpreter(cmd: &str) -> (String, String) {
-    let lines: Vec<&str> = cmd.split('\n').collect();
-    if lines[0].starts_with("#!") {
-        (lines[0][2..].to_string(), lines[1..].join("\n"))
+/// Runs `command` and returns its stdout, or its stderr as the error when it
+/// exits unsuccessfully. `display` names the command in messages.
+pub fn run_command(command: Command, display: &str, options: &RunOptions) -> tera::Result<String> {
+    let output = run_captured(command, display, options)?;
+    if output.status == Some(0) {
+        Ok(output.stdout)
     } else {
-        ("sh".to_string(), cmd.to_string())
+        Err(output.failure(display))
     }
 }
 
@@ -107,3 +535,31 @@ pub fn run_with_interpreter(
     }
     Ok(command)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits_cover_background_children() {
+        let options = RunOptions {
+            name: "background".to_string(),
+            timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let command = run_with_interpreter("sh", "sleep 100 & echo hi", None).unwrap();
+        let started = Instant::now();
+        let err = run_command(command, "sleep", &options).unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let options = RunOptions {
+            name: "background".to_string(),
+            max_output: Some(1024),
+            ..Default::default()
+        };
+        let command = run_with_interpreter("sh", "(sleep 0.2; yes) & echo hi", None).unwrap();
+        let err = run_command(command, "yes", &options).unwrap_err();
+        assert!(err.to_string().contains("more than 1024 bytes"), "{}", err);
+    }
+}
@@ -1,5 +1,7 @@
 // src/function.rs
-use crate::command::execute_shell_command;
+use crate::ast::{EnvPolicy, ResourceLimits, ShellOptions};
+use crate::command::{execute_shell_command, RunOptions};
+use crate::host::HostEnv;
 use anyhow::Context as _Context;
 use log::{debug, info};
 use std::collections::HashMap;
@@ -23,7 +25,21 @@ fn read_file(args: &HashMap<String, TeraValue>) -> Result<TeraValue> {
     Ok(TeraValue::String(content))
 }
 
-fn shell(args: &HashMap<String, TeraValue>) -> Result<TeraValue> {
+/// Arguments of `shell()` that are not passed on as environment variables.
+const SHELL_OPTIONS: &[&str] = &[
+    "cmd",
+    "interpreter",
+    "timeout",
+    "max_output",
+    "cpu_seconds",
+    "address_space_mb",
+];
+
+fn shell(
+    args: &HashMap<String, TeraValue>,
+    env_policy: &EnvPolicy,
+    host: &HostEnv,
+) -> Result<TeraValue> {
     let cmd = match args.get("cmd") {
         Some(TeraValue::String(s)) => s,
         _ => return Err(tera::Error::msg("cmd must be provided and be a string")),
@@ -34,16 +50,42 @@ fn shell(args: &HashMap<String, TeraValue>) -> Result<TeraValue> {
         _ => Some("sh"),
     };
 
-    let mut env: HashMap<String, String> = HashMap::new();
+    let number = |key: &str| -> Result<Option<f64>> {
+        match args.get(key) {
+            None => Ok(None),
+            Some(value) => value
+                .as_f64()
+                .filter(|n| *n >= 0.0)
+                .map(Some)
+                .ok_or_else(|| Error::msg(format!("shell: {} must be a non-negative number", key))),
+        }
+    };
+    let options = ShellOptions {
+        timeout: number("timeout")?,
+        max_output: number("max_output")?.map(|n| n as usize),
+        limits: ResourceLimits {
+            cpu_seconds: number("cpu_seconds")?.map(|n| n as u64),
+            address_space_mb: number("address_space_mb")?.map(|n| n as u64),
+        },
+        ..Default::default()
+    };
+
+    let mut env: HashMap<String, String> = host
+        .env_from_vars(env_policy)
+        .map_err(|e| Error::msg(format!("shell: {}", e)))?;
     for (k, v) in args.iter() {
-        if k != "cmd" && k != "interpreter" {
+        if !SHELL_OPTIONS.contains(&k.as_str()) {
             if let TeraValue::String(s) = v {
                 env.insert(k.clone(), s.clone());
             }
         }
     }
 
-    match execute_shell_command(cmd, &Some(env), interpreter) {
+    let options = RunOptions {
+        env_policy: env_policy.clone(),
+        ..RunOptions::from_shell_options("shell", &options)?
+    };
+    match execute_shell_command(cmd, &Some(env), interpreter, &options) {
         Ok(output) => Ok(TeraValue::String(output)),
         Err(e) => Err(e),
     }
@@ -52,7 +94,15 @@ fn shell(args: &HashMap<String, TeraValue>) -> Result<TeraValue> {
 pub fn register_functions(tera: &mut tera::Tera) {
     info!("register builtin-functions");
     tera.register_function("read_file", read_file);
-    tera.register_function("shell", shell);
+    register_shell(tera, EnvPolicy::default(), HostEnv::default());
+}
+
+/// (Re-)registers `shell()` so that its commands run under `env_policy`,
+/// with its `env_from_vars` read from `host`.
+pub fn register_shell(tera: &mut tera::Tera, env_policy: EnvPolicy, host: HostEnv) {
+    tera.register_function("shell", move |args: &HashMap<String, TeraValue>| {
+        shell(args, &env_policy, &host)
+    });
 }
 
 #[cfg(test)]
@@ -69,7 +119,7 @@ mod tests {
             TeraValue::String("echo hello".to_string()),
         );
 
-        let result = shell(&args);
+        let result = shell(&args, &EnvPolicy::default(), &HostEnv::default());
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), TeraValue::String("hello\n".to_string()));
 
@@ -78,8 +128,56 @@ mod tests {
             TeraValue::String("/bin/bash".to_string()),
         );
 
-        let result = shell(&args);
+        let result = shell(&args, &EnvPolicy::default(), &HostEnv::default());
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), TeraValue::String("hello\n".to_string()));
     }
+
+    #[test]
+    fn test_shell_limits() {
+        let mut args = HashMap::new();
+        args.insert("cmd".to_string(), TeraValue::from("sleep 5 & sleep 5"));
+        args.insert("timeout".to_string(), TeraValue::from(0.2));
+        let started = std::time::Instant::now();
+        let err = shell(&args, &EnvPolicy::default(), &HostEnv::default())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("timed out"), "{}", err);
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+
+        let mut args = HashMap::new();
+        args.insert("cmd".to_string(), TeraValue::from("yes"));
+        args.insert("max_output".to_string(), TeraValue::from(1024));
+        let err = shell(&args, &EnvPolicy::default(), &HostEnv::default())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("more than 1024 bytes"), "{}", err);
+
+        let mut args = HashMap::new();
+        args.insert("cmd".to_string(), TeraValue::from("echo $timeout"));
+        args.insert("timeout".to_string(), TeraValue::from(5));
+        assert_eq!(
+            shell(&args, &EnvPolicy::default(), &HostEnv::default()).unwrap(),
+            TeraValue::from("\n")
+        );
+    }
+
+    #[test]
+    fn test_shell_env_from_vars() {
+        let policy: EnvPolicy =
+            serde_yaml::from_str("{inherit: none, env_from_vars: {TOKEN: registry.token}}")
+                .unwrap();
+        let host = HostEnv::default();
+        let mut args = HashMap::new();
+        args.insert("cmd".to_string(), TeraValue::from("echo \"$TOKEN\""));
+        let err = shell(&args, &policy, &host).unwrap_err().to_string();
+        assert!(err.contains("registry.token"), "{}", err);
+
+        host.set_vars(&serde_json::json!({"registry": {"token": "s3cret"}}))
+            .unwrap();
+        assert_eq!(
+            shell(&args, &policy, &host).unwrap(),
+            TeraValue::from("s3cret\n")
+        );
+    }
 }
@@ -0,0 +1,269 @@
+// src/host.rs
+use crate::ast::{ContextItem, EnvPolicy, Executable};
+use crate::cache::{sha256_hex, Cache};
+use crate::command::tera_to_str;
+use log::debug;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tera::{Context, Tera, Value};
+
+/// Render state that plugins can call back into: the registered Tera
+/// functions/filters and the current `vars`, plus the cache of their results.
+#[derive(Clone, Default)]
+pub struct HostEnv {
+    inner: Arc<RwLock<HostState>>,
+    cache: Cache,
+}
+
+#[derive(Default)]
+struct HostState {
+    /// Shared by the renders in progress; see `HostEnv::prepare`.
+    tera: Option<Arc<Tera>>,
+    vars: Value,
+    template_name: Option<String>,
+    output_path: Option<String>,
+}
+
+impl std::fmt::Debug for HostEnv {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HostEnv").finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallRequest {
+    pub name: String,
+    #[serde(default)]
+    pub args: tera::Map<String, Value>,
+    #[serde(default)]
+    pub filter: bool,
+    #[serde(default)]
+    pub value: Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenderRequest {
+    pub template: String,
+    #[serde(default)]
+    pub context: tera::Map<String, Value>,
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl HostEnv {
+    pub fn with_cache(cache: Cache) -> Self {
+        HostEnv {
+            inner: Default::default(),
+            cache,
+        }
+    }
+
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    /// Snapshots `tera` so that plugins can call whatever is registered on it.
+    pub fn set_tera(&self, tera: &Tera) {
+        self.inner.write().unwrap().tera = Some(Arc::new(tera.clone()));
+    }
+
+    pub fn set_vars<T: serde::Serialize>(&self, vars: &T) -> tera::Result<()> {
+        self.inner.write().unwrap().vars = serde_json::to_value(vars)?;
+        Ok(())
+    }
+
+    /// Records what is being rendered, for declarations asking for
+    /// `template_name` or `output_path`.
+    pub fn set_target(&self, template_name: &str, output_path: Option<&str>) {
+        let mut state = self.inner.write().unwrap();
+        state.template_name = Some(template_name.to_string());
+        state.output_path = output_path.map(ToOwned::to_owned);
+    }
+
+    /// The requested parts of the render state, as an object keyed by the
+    /// names used in `context:`.
+    pub fn context(&self, items: &[ContextItem]) -> Value {
+        let state = self.inner.read().unwrap();
+        let mut context = tera::Map::new();
+        for item in items {
+            let (key, value) = match item {
+                ContextItem::Vars => ("vars", state.vars.clone()),
+                ContextItem::TemplateName => ("template_name", state.template_name.clone().into()),
+                ContextItem::OutputPath => ("output_path", state.output_path.clone().into()),
+            };
+            context.insert(key.to_string(), value);
+        }
+        Value::Object(context)
+    }
+
+    /// Looks up a dotted path such as `registry.url` in `vars`.
+    pub fn get_var(&self, path: &str) -> Value {
+        let state = self.inner.read().unwrap();
+        path.split('.')
+            .try_fold(&state.vars, |value, key| match value {
+                Value::Object(map) => map.get(key),
+                Value::Array(arr) => key.parse::<usize>().ok().and_then(|i| arr.get(i)),
+                _ => None,
+            })
+            .cloned()
+            .unwrap_or(Value::Null)
+    }
+
+    /// The variables `policy` sets from `vars` entries, each of which must be
+    /// set.
+    pub fn env_from_vars(&self, policy: &EnvPolicy) -> tera::Result<HashMap<String, String>> {
+        let mut env = HashMap::new();
+        for (name, path) in policy.env_from_vars.iter().flatten() {
+            match self.get_var(path) {
+                Value::Null => {
+                    return Err(tera::Error::msg(format!(
+                        "vars entry '{}' for {} is not set",
+                        path, name
+                    )))
+                }
+                value => env.insert(name.clone(), tera_to_str(&value)?),
+            };
+        }
+        Ok(env)
+    }
+
+    /// The environment with `template` added under a name derived from its
+    /// text, and that name. Each distinct template is parsed once and later
+    /// renders share the environment; it is only copied when a template is
+    /// added while another render still uses it.
+    fn prepare(&self, template: &str) -> tera::Result<(Arc<Tera>, String)> {
+        let not_ready = || tera::Error::msg("template environment is not ready yet");
+        let name = format!("__host_{}", sha256_hex(template.as_bytes()));
+        {
+            let state = self.inner.read().unwrap();
+            let tera = state.tera.as_ref().ok_or_else(not_ready)?;
+            if tera.get_template(&name).is_ok() {
+                return Ok((tera.clone(), name));
+            }
+        }
+        let mut state = self.inner.write().unwrap();
+        let tera = state.tera.as_mut().ok_or_else(not_ready)?;
+        Arc::make_mut(tera).add_raw_template(&name, template)?;
+        Ok((tera.clone(), name))
+    }
+
+    fn render_with(&self, template: &str, mut context: Context) -> tera::Result<String> {
+        let (tera, name) = self.prepare(template)?;
+        context.insert("vars", &self.inner.read().unwrap().vars);
+        tera.render(&name, &context)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn tera(&self) -> Option<Arc<Tera>> {
+        self.inner.read().unwrap().tera.clone()
+    }
+
+    /// Calls a registered function, or filter when `request.filter` is set.
+    pub fn call(&self, request: &CallRequest) -> tera::Result<Value> {
+        for name in std::iter::once(&request.name).chain(request.args.keys()) {
+            if !is_identifier(name) {
+                return Err(tera::Error::msg(format!("invalid identifier: '{}'", name)));
+            }
+        }
+        let kwargs = request
+            .args
+            .keys()
+            .map(|k| format!("{}=__args.{}", k, k))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let template = if request.filter {
+            format!(
+                "{{{{ __value | {}({}) | json_encode() | safe }}}}",
+                request.name, kwargs
+            )
+        } else {
+            format!(
+                "{{{{ {}({}) | json_encode() | safe }}}}",
+                request.name, kwargs
+            )
+        };
+        debug!("host call: {}", template);
+
+        let mut context = Context::new();
+        context.insert("__args", &request.args);
+        context.insert("__value", &request.value);
+        let rendered = self.render_with(&template, context)?;
+        Ok(serde_json::from_str(&rendered)?)
+    }
+
+    /// Renders `request.template` with `vars` and `request.context` in scope.
+    pub fn render(&self, request: &RenderRequest) -> tera::Result<String> {
+        let context = Context::from_value(Value::Object(request.context.clone()))?;
+        self.render_with(&request.template, context)
+    }
+}
+
+/// Hands the render context listed in a declaration's `context:` to the
+/// executable on every call.
+pub struct ContextExecutable {
+    pub executor: Box<dyn Executable>,
+    pub items: Vec<ContextItem>,
+    pub host: HostEnv,
+}
+
+impl Executable for ContextExecutable {
+    fn execute(&self, args: &HashMap<String, Value>, value: Option<&Value>) -> tera::Result<Value> {
+        let context = self.host.context(&self.items);
+        self.executor
+            .execute_in_context(args, value, Some(&context))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host() -> HostEnv {
+        let mut tera = Tera::default();
+        tera.register_function("greet", |args: &HashMap<String, Value>| {
+            Ok(Value::String(format!(
+                "hello {}",
+                args.get("who").and_then(Value::as_str).unwrap_or("nobody")
+            )))
+        });
+        let host = HostEnv::default();
+        host.set_tera(&tera);
+        host.set_vars(&serde_json::json!({"registry": {"url": "r.example"}}))
+            .unwrap();
+        host
+    }
+
+    #[test]
+    fn test_host_callbacks() {
+        let host = host();
+        assert_eq!(host.get_var("registry.url"), Value::from("r.example"));
+        assert_eq!(host.get_var("registry.missing"), Value::Null);
+
+        let request: CallRequest =
+            serde_json::from_str(r#"{"name": "greet", "args": {"who": "wasm"}}"#).unwrap();
+        assert_eq!(host.call(&request).unwrap(), Value::from("hello wasm"));
+
+        let request: CallRequest =
+            serde_json::from_str(r#"{"name": "upper", "filter": true, "value": "abc"}"#).unwrap();
+        assert_eq!(host.call(&request).unwrap(), Value::from("ABC"));
+
+        let request: RenderRequest = serde_json::from_str(
+            r#"{"template": "{{ vars.registry.url }}/{{ tag }}", "context": {"tag": "v1"}}"#,
+        )
+        .unwrap();
+        assert_eq!(host.render(&request).unwrap(), "r.example/v1");
+        // A template seen before is rendered from the shared environment.
+        let shared = host.tera().unwrap();
+        assert_eq!(host.render(&request).unwrap(), "r.example/v1");
+        assert!(Arc::ptr_eq(&shared, &host.tera().unwrap()));
+
+        let request: CallRequest =
+            serde_json::from_str(r#"{"name": "greet() }}{{ greet"}"#).unwrap();
+        assert!(host.call(&request).is_err());
+    }
+}
@@ -0,0 +1,846 @@
+// src/http_plugin.rs
+use crate::ast::{Executable, ExportKind, HttpConfig, ResultMode, ShellOptions};
+use crate::command::parse_output;
+use crate::host::{HostEnv, RenderRequest};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use tera::Value;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Methods that do not change the service's state, and so can be retried.
+const SAFE_METHODS: &[&str] = &["GET", "HEAD", "OPTIONS", "TRACE"];
+
+/// A function, filter or tester answered by an HTTP service over plain
+/// HTTP/1.1, on TCP or a unix domain socket. Testers pass on a 2xx status.
+pub struct HttpExecutable {
+    name: String,
+    kind: ExportKind,
+    config: HttpConfig,
+    /// `output`, `result`, `allow_failure` and `max_output` apply to the body.
+    options: ShellOptions,
+    timeout: Duration,
+    retry_delay: Duration,
+    host: HostEnv,
+}
+
+struct Request {
+    method: String,
+    /// `host[:port]` to connect to, unless a unix socket is used.
+    address: Option<(String, u16)>,
+    host_header: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Option<String>,
+}
+
+struct Response {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+trait Connection: Read + Write {
+    fn set_timeouts(&self, timeout: Duration) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_timeouts(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+}
+
+#[cfg(unix)]
+impl Connection for std::os::unix::net::UnixStream {
+    fn set_timeouts(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+}
+
+/// Reads from a connection until `deadline`.
+struct Timed<C> {
+    inner: C,
+    deadline: Instant,
+}
+
+impl<C: Connection> Read for Timed<C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+        }
+        self.inner.set_timeouts(left)?;
+        self.inner.read(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock => io::Error::new(io::ErrorKind::TimedOut, "timed out"),
+            _ => e,
+        })
+    }
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Splits `http://host[:port]/path` into its address and path.
+fn split_url(url: &str) -> Result<(String, u16, String), String> {
+    let rest = match url.strip_prefix("http://") {
+        Some(rest) => rest,
+        None if url.starts_with("https://") => {
+            return Err(format!(
+                "https URLs are not supported, only plain http: '{}'",
+                url
+            ))
+        }
+        None => return Err(format!("unsupported URL '{}'", url)),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (
+            host,
+            port.parse::<u16>()
+                .map_err(|_| format!("invalid port in URL '{}'", url))?,
+        ),
+        _ => (authority, 80),
+    };
+    Ok((host.to_string(), port, path.to_string()))
+}
+
+fn read_line(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(invalid(
+            "connection closed before the response was complete",
+        ));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Appends at most `len` bytes (everything when `None`) to `body`, failing
+/// once it holds more than `max` bytes.
+fn read_body(
+    reader: &mut impl Read,
+    len: Option<u64>,
+    max: Option<usize>,
+    body: &mut Vec<u8>,
+) -> io::Result<()> {
+    let limit = max.map_or(u64::MAX, |max| (max + 1).saturating_sub(body.len()) as u64);
+    let wanted = len.unwrap_or(u64::MAX);
+    let read = reader.take(wanted.min(limit)).read_to_end(body)?;
+    if let Some(max) = max {
+        if body.len() > max {
+            return Err(invalid(format!(
+                "response body is larger than {} bytes",
+                max
+            )));
+        }
+    }
+    if len.is_some_and(|len| (read as u64) < len) {
+        return Err(invalid(
+            "connection closed before the response was complete",
+        ));
+    }
+    Ok(())
+}
+
+fn exchange(
+    mut conn: impl Connection,
+    request: &Request,
+    deadline: Instant,
+    max: Option<usize>,
+) -> io::Result<Response> {
+    conn.set_timeouts(
+        deadline
+            .saturating_duration_since(Instant::now())
+            .max(Duration::from_millis(1)),
+    )?;
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nUser-Agent: jintemplify/{}\r\n",
+        request.method,
+        request.path,
+        request.host_header,
+        env!("CARGO_PKG_VERSION")
+    );
+    for (name, value) in request.headers.iter() {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if let Some(body) = &request.body {
+        head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    head.push_str("\r\n");
+    conn.write_all(head.as_bytes())?;
+    if let Some(body) = &request.body {
+        conn.write_all(body.as_bytes())?;
+    }
+    conn.flush()?;
+
+    let mut reader = BufReader::new(Timed {
+        inner: conn,
+        deadline,
+    });
+    let status_line = read_line(&mut reader)?;
+    let mut parts = status_line.splitn(3, ' ');
+    let status = match (parts.next(), parts.next()) {
+        (Some(version), Some(code)) if version.starts_with("HTTP/") => code
+            .parse::<u16>()
+            .map_err(|_| invalid(format!("invalid status line '{}'", status_line)))?,
+        _ => return Err(invalid(format!("invalid status line '{}'", status_line))),
+    };
+    let reason = parts.next().unwrap_or_default().to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(&mut reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid(format!("invalid header '{}'", line)))?;
+        headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+
+    let mut body = Vec::new();
+    if request.method == "HEAD" || status == 204 || status == 304 || (100..200).contains(&status) {
+        // No body.
+    } else if header("transfer-encoding").is_some_and(|te| te.eq_ignore_ascii_case("chunked")) {
+        loop {
+            let line = read_line(&mut reader)?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = u64::from_str_radix(size, 16)
+                .map_err(|_| invalid(format!("invalid chunk size '{}'", line)))?;
+            if size == 0 {
+                while !read_line(&mut reader)?.is_empty() {}
+                break;
+            }
+            read_body(&mut reader, Some(size), max, &mut body)?;
+            read_line(&mut reader)?;
+        }
+    } else {
+        let len = header("content-length")
+            .map(|len| {
+                len.parse::<u64>()
+                    .map_err(|_| invalid(format!("invalid Content-Length '{}'", len)))
+            })
+            .transpose()?;
+        read_body(&mut reader, len, max, &mut body)?;
+    }
+    Ok(Response {
+        status,
+        reason,
+        headers,
+        body,
+    })
+}
+
+fn duration(
+    name: &str,
+    key: &str,
+    secs: Option<f64>,
+    default: Duration,
+) -> anyhow::Result<Duration> {
+    match secs {
+        Some(secs) => Duration::try_from_secs_f64(secs)
+            .map_err(|e| anyhow::anyhow!("'{}': invalid {} {}: {}", name, key, secs, e)),
+        None => Ok(default),
+    }
+}
+
+impl HttpExecutable {
+    /// Checks the method, the retry policy, the URL scheme and the syntax of
+    /// the templates up front.
+    pub fn new(
+        name: &str,
+        kind: ExportKind,
+        config: &HttpConfig,
+        options: &ShellOptions,
+        host: &HostEnv,
+    ) -> anyhow::Result<Self> {
+        let mut config = config.clone();
+        config.method = config.method.to_uppercase();
+        if config.method.is_empty() || !config.method.chars().all(|c| c.is_ascii_alphabetic()) {
+            anyhow::bail!("'{}': invalid HTTP method '{}'", name, config.method);
+        }
+        if config.retries > 0
+            && !config.retry_unsafe
+            && !SAFE_METHODS.contains(&config.method.as_str())
+        {
+            anyhow::bail!(
+                "'{}': {} requests are only retried with retry_unsafe: true",
+                name,
+                config.method
+            );
+        }
+        if config.url.starts_with("https://") {
+            anyhow::bail!("'{}': https URLs are not supported, only plain http", name);
+        }
+        let templates = std::iter::once(&config.url)
+            .chain(config.body.iter())
+            .chain(config.headers.iter().flat_map(|headers| headers.values()));
+        for template in templates {
+            tera::Tera::default()
+                .add_raw_template(name, template)
+                .map_err(|e| {
+                    anyhow::Error::new(e).context(format!("'{}': invalid template", name))
+                })?;
+        }
+        if cfg!(not(unix)) && config.unix_socket.is_some() {
+            anyhow::bail!("'{}': unix_socket is only supported on unix", name);
+        }
+        Ok(HttpExecutable {
+            name: name.to_string(),
+            kind,
+            timeout: duration(name, "timeout", config.timeout, DEFAULT_TIMEOUT)?,
+            retry_delay: duration(name, "retry_delay", config.retry_delay, DEFAULT_RETRY_DELAY)?,
+            config,
+            options: options.clone(),
+            host: host.clone(),
+        })
+    }
+
+    fn error(&self, message: impl std::fmt::Display) -> tera::Error {
+        tera::Error::msg(format!("Plugin '{}': {}", self.name, message))
+    }
+
+    fn render(&self, template: &str, context: &tera::Map<String, Value>) -> tera::Result<String> {
+        let rendered = self
+            .host
+            .render(&RenderRequest {
+                template: template.to_string(),
+                context: context.clone(),
+            })
+            .map_err(|e| tera::Error::chain(format!("Plugin '{}'", self.name), e))?;
+        Ok(rendered)
+    }
+
+    /// Renders a URL or header value, which must stay on one line.
+    fn render_line(
+        &self,
+        template: &str,
+        context: &tera::Map<String, Value>,
+    ) -> tera::Result<String> {
+        let rendered = self.render(template, context)?;
+        if rendered.contains(['\r', '\n']) {
+            return Err(self.error(format!("'{}' must not render to line breaks", template)));
+        }
+        Ok(rendered)
+    }
+
+    fn request(&self, context: &tera::Map<String, Value>) -> tera::Result<Request> {
+        let url = self.render_line(&self.config.url, context)?;
+        let (address, host_header, path) = match (&self.config.unix_socket, url.starts_with('/')) {
+            (Some(_), true) => (None, "localhost".to_string(), url),
+            (unix_socket, _) => {
+                let (host, port, path) = split_url(&url).map_err(|e| self.error(e))?;
+                let host_header = if port == 80 {
+                    host.clone()
+                } else {
+                    format!("{}:{}", host, port)
+                };
+                let address = unix_socket.is_none().then_some((host, port));
+                (address, host_header, path)
+            }
+        };
+        if path.contains(char::is_whitespace) {
+            return Err(self.error(format!(
+                "URL path '{}' must not contain whitespace; use the urlencode filter",
+                path
+            )));
+        }
+        let mut headers = Vec::new();
+        for (name, value) in self.config.headers.iter().flatten() {
+            headers.push((name.clone(), self.render_line(value, context)?));
+        }
+        let body = self
+            .config
+            .body
+            .as_ref()
+            .map(|body| self.render(body, context))
+            .transpose()?;
+        Ok(Request {
+            method: self.config.method.clone(),
+            address,
+            host_header,
+            path,
+            headers,
+            body,
+        })
+    }
+
+    fn send(&self, request: &Request) -> io::Result<Response> {
+        let deadline = Instant::now() + self.timeout;
+        let max = self.options.max_output;
+        match (&request.address, &self.config.unix_socket) {
+            (Some((host, port)), _) => {
+                let mut last = io::Error::new(io::ErrorKind::NotFound, "no address found");
+                for addr in (host.trim_matches(['[', ']']), *port).to_socket_addrs()? {
+                    match TcpStream::connect_timeout(&addr, self.timeout) {
+                        Ok(conn) => return exchange(conn, request, deadline, max),
+                        Err(e) => last = e,
+                    }
+                }
+                Err(last)
+            }
+            #[cfg(unix)]
+            (None, Some(path)) => exchange(
+                std::os::unix::net::UnixStream::connect(path)?,
+                request,
+                deadline,
+                max,
+            ),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "no address to connect to",
+            )),
+        }
+    }
+
+    /// Turns the response into the value of the call, according to
+    /// `allow_failure`, `output` and `result`.
+    fn result(&self, response: Response, display: &str) -> tera::Result<Value> {
+        let success = (200..300).contains(&response.status);
+        if self.kind == ExportKind::Tester {
+            return Ok(Value::Bool(success));
+        }
+        let text = String::from_utf8_lossy(&response.body).into_owned();
+        if !success && !self.options.allow_failure {
+            let excerpt: String = text.chars().take(200).collect();
+            return Err(self.error(format!(
+                "{} returned {} {}: {}",
+                display, response.status, response.reason, excerpt
+            )));
+        }
+        let body = parse_output(&self.name, self.options.output, text)?;
+        Ok(match self.options.result {
+            ResultMode::Output => body,
+            ResultMode::Full => serde_json::json!({
+                "status": response.status,
+                "headers": response
+                    .headers
+                    .into_iter()
+                    .map(|(name, value)| (name, Value::String(value)))
+                    .collect::<tera::Map<_, _>>(),
+                "body": body,
+            }),
+        })
+    }
+}
+
+/// Whether a response is worth another attempt.
+fn retryable(status: u16) -> bool {
+    status == 429 || (500..600).contains(&status)
+}
+
+impl Executable for HttpExecutable {
+    fn execute(&self, args: &HashMap<String, Value>, value: Option<&Value>) -> tera::Result<Value> {
+        self.execute_in_context(args, value, None)
+    }
+
+    fn execute_in_context(
+        &self,
+        args: &HashMap<String, Value>,
+        value: Option<&Value>,
+        render_context: Option<&Value>,
+    ) -> tera::Result<Value> {
+        let mut context: tera::Map<String, Value> = args.clone().into_iter().collect();
+        if let Some(value) = value {
+            context.insert("input".to_string(), value.clone());
+        }
+        if let Some(render_context) = render_context {
+            context.insert("context".to_string(), render_context.clone());
+        }
+        let request = self.request(&context)?;
+        let display = format!("{} {}", request.method, request.path);
+        debug!("==> {}: {}", self.name, display);
+
+        let mut delay = self.retry_delay;
+        let mut attempt = 0;
+        let outcome = loop {
+            let outcome = self.send(&request);
+            let problem = match &outcome {
+                Ok(response) if retryable(response.status) => {
+                    format!("{} {}", response.status, response.reason)
+                }
+                Ok(_) => break outcome,
+                Err(e) => e.to_string(),
+            };
+            if attempt >= self.config.retries {
+                break outcome;
+            }
+            attempt += 1;
+            warn!(
+                "Plugin '{}': {} failed ({}), retrying in {:.1?}",
+                self.name, display, problem, delay
+            );
+            std::thread::sleep(delay);
+            delay = delay.saturating_mul(2);
+        };
+        match outcome {
+            Ok(response) => self.result(response, &display),
+            Err(e) => Err(self.error(format!("{} failed: {}", display, e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tera::{Function, Test};
+
+    /// Answers one connection per response, in order, and returns the
+    /// requests it received.
+    fn stand_in<S: std::io::Read + std::io::Write>(
+        mut accept: impl FnMut() -> S + Send + 'static,
+        responses: Vec<&'static str>,
+    ) -> std::thread::JoinHandle<Vec<String>> {
+        use std::io::{BufRead, Read};
+        std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let mut conn = accept();
+                let mut reader = std::io::BufReader::new(&mut conn);
+                let mut request = String::new();
+                let mut len = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.strip_prefix("Content-Length: ") {
+                        len = value.trim().parse().unwrap();
+                    }
+                    request.push_str(&line);
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+                request.push_str(&String::from_utf8(body).unwrap());
+                requests.push(request);
+                conn.write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        })
+    }
+
+    fn executable(yaml: &str) -> anyhow::Result<HttpExecutable> {
+        let config: HttpConfig = serde_yaml::from_str(yaml).unwrap();
+        let host = HostEnv::default();
+        host.set_tera(&tera::Tera::default());
+        HttpExecutable::new(
+            "service",
+            ExportKind::Function,
+            &config,
+            &ShellOptions::default(),
+            &host,
+        )
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        assert!(executable("{url: 'http://localhost/', retries: 2}").is_ok());
+        let err = executable("{method: post, url: 'http://localhost/', retries: 2}")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "'service': POST requests are only retried with retry_unsafe: true"
+        );
+        assert!(executable(
+            "{method: put, url: 'http://localhost/', retries: 2, retry_unsafe: true}"
+        )
+        .is_ok());
+        assert!(executable("{method: patch, url: 'http://localhost/'}").is_ok());
+    }
+
+    #[test]
+    fn test_https_is_rejected() {
+        let err = executable("{url: 'https://localhost/'}").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "'service': https URLs are not supported, only plain http"
+        );
+        assert_eq!(
+            split_url("https://example.com/").unwrap_err(),
+            "https URLs are not supported, only plain http: 'https://example.com/'"
+        );
+    }
+
+    /// A connection that answers with `response` and discards the request.
+    struct Scripted(io::Cursor<&'static str>);
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Scripted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Scripted {
+        fn set_timeouts(&self, _timeout: Duration) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn get(response: &'static str, max: Option<usize>) -> io::Result<Response> {
+        let request = Request {
+            method: "GET".to_string(),
+            address: None,
+            host_header: "localhost".to_string(),
+            path: "/".to_string(),
+            headers: Vec::new(),
+            body: None,
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        exchange(Scripted(io::Cursor::new(response)), &request, deadline, max)
+    }
+
+    #[test]
+    fn test_chunked_body() {
+        let chunked = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                       6;ext=1\r\n{\"id\":\r\n2\r\n7}\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let response = get(chunked, None).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"{\"id\":7}");
+
+        let err = get(chunked, Some(7)).err().unwrap();
+        assert_eq!(err.to_string(), "response body is larger than 7 bytes");
+        let err = get(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+            None,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.to_string(), "invalid chunk size 'zz'");
+        let err = get(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n6\r\nab",
+            None,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "connection closed before the response was complete"
+        );
+    }
+
+    #[test]
+    fn test_line_breaks_are_rejected() {
+        let service =
+            executable("{url: 'http://localhost/{{ path }}', headers: {X-Token: '{{ token }}'}}")
+                .unwrap();
+        let context = |path: &str, token: &str| {
+            serde_json::from_value(serde_json::json!({"path": path, "token": token})).unwrap()
+        };
+
+        assert!(service.request(&context("a", "t0k")).is_ok());
+        let err = service
+            .request(&context("a", "t0k\r\nX-Admin: 1"))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Plugin 'service': '{{ token }}' must not render to line breaks"
+        );
+        let err = service
+            .request(&context("a HTTP/1.1\r\nX-Admin: 1\r\n\r\nGET /b", "t0k"))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("line breaks"), "{}", err);
+    }
+
+    #[test]
+    fn test_headers_share_the_environment() {
+        let service = executable(
+            "{url: 'http://localhost/{{ path }}', headers: {X-A: '{{ a }}', X-B: '{{ b }}-{{ a }}', X-C: c}}",
+        )
+        .unwrap();
+        let context =
+            serde_json::from_value(serde_json::json!({"path": "p", "a": "1", "b": "2"})).unwrap();
+        let mut headers = service.request(&context).unwrap().headers;
+        headers.sort();
+        let expected = [("X-A", "1"), ("X-B", "2-1"), ("X-C", "c")];
+        assert_eq!(
+            headers,
+            expected.map(|(name, value)| (name.to_string(), value.to_string()))
+        );
+
+        // Later requests render from the same environment instead of a copy.
+        let shared = service.host.tera().unwrap();
+        let request = service.request(&context).unwrap();
+        assert_eq!(request.path, "/p");
+        assert!(std::sync::Arc::ptr_eq(
+            &shared,
+            &service.host.tera().unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_retries_back_off() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = stand_in(
+            move || listener.accept().unwrap().0,
+            vec![
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n",
+                "HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\n\r\n",
+                "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+                "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n",
+                "HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n",
+            ],
+        );
+        let service = executable(&format!(
+            "{{url: 'http://127.0.0.1:{}/', retries: 2, retry_delay: 0.05, timeout: 5}}",
+            port
+        ))
+        .unwrap();
+
+        // Two retries, 0.05 then 0.1 seconds apart.
+        let started = Instant::now();
+        assert_eq!(
+            service.execute(&HashMap::new(), None).unwrap(),
+            Value::from("ok")
+        );
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        // A 404 is final.
+        let err = service.execute(&HashMap::new(), None).unwrap_err();
+        assert!(err.to_string().contains("404 Not Found"), "{}", err);
+
+        let service = executable(&format!(
+            "{{url: 'http://127.0.0.1:{}/', retries: 1, retry_delay: 0.01, timeout: 5}}",
+            port
+        ))
+        .unwrap();
+        let err = service.execute(&HashMap::new(), None).unwrap_err();
+        assert!(err.to_string().contains("502 Bad Gateway"), "{}", err);
+        assert_eq!(server.join().unwrap().len(), 6);
+    }
+
+    #[test]
+    fn test_http_declarations() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = stand_in(
+            move || listener.accept().unwrap().0,
+            vec![
+                "HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\n{\"id\":7}",
+                "HTTP/1.1 201 Created\r\nContent-Length: 7\r\nX-Id: 8\r\n\r\ncreated",
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 7\r\n\r\nno such",
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+            ],
+        );
+        let plugin: crate::plugin::Plugin = serde_yaml::from_str(&format!(
+            r#"
+functions:
+  - name: item
+    params:
+      - name: id
+        type: int
+    http:
+      url: "http://127.0.0.1:{port}/items/{{{{ id }}}}"
+      headers:
+        X-Token: "{{{{ vars.token }}}}"
+    output: json
+  - name: create
+    params:
+      - name: name
+    http:
+      method: post
+      url: "http://127.0.0.1:{port}/items"
+      body: '{{"name": {{{{ name | json_encode() | safe }}}}}}'
+    result: full
+  - name: missing
+    http:
+      url: "http://127.0.0.1:{port}/missing"
+testers:
+  - name: known
+    http:
+      url: "http://127.0.0.1:{port}/items/{{{{ input }}}}"
+"#
+        ))
+        .unwrap();
+        let host = HostEnv::default();
+        host.set_tera(&tera::Tera::default());
+        host.set_vars(&serde_json::json!({"token": "t0k"})).unwrap();
+        let functions: HashMap<_, _> = plugin
+            .functions
+            .unwrap()
+            .iter()
+            .map(|decl| (decl.name.clone(), decl.create_function(&host).unwrap()))
+            .collect();
+        let call = |name: &str, args: serde_json::Value| {
+            functions[name].call(&serde_json::from_value(args).unwrap())
+        };
+
+        assert_eq!(
+            call("item", serde_json::json!({"id": 7})).unwrap(),
+            serde_json::json!({"id": 7})
+        );
+        assert_eq!(
+            call("create", serde_json::json!({"name": "a\"b"})).unwrap(),
+            serde_json::json!({"status": 201, "headers": {"content-length": "7", "x-id": "8"}, "body": "created"})
+        );
+        let err = call("missing", serde_json::json!({})).unwrap_err();
+        assert!(
+            err.to_string().contains("404 Not Found: no such"),
+            "{}",
+            err
+        );
+        let tester = plugin.testers.unwrap()[0].create_tester(&host).unwrap();
+        assert!(!tester.test(Some(&Value::from(9)), &[]).unwrap());
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /items/7 HTTP/1.1\r\n"));
+        assert!(requests[0].contains("\r\nX-Token: t0k\r\n"));
+        assert!(requests[1].starts_with("POST /items HTTP/1.1\r\n"));
+        assert!(requests[1].ends_with("\r\n\r\n{\"name\": \"a\\\"b\"}"));
+        assert!(requests[2].starts_with("GET /missing "));
+
+        #[cfg(unix)]
+        {
+            let dir = tempfile::tempdir().unwrap();
+            let socket = dir.path().join("service.sock");
+            let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+            let server = stand_in(
+                move || listener.accept().unwrap().0,
+                vec!["HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\npong"],
+            );
+            let ping = crate::ast::Declaration {
+                name: "ping".to_string(),
+                http: Some(
+                    serde_yaml::from_str(&format!("{{url: /ping, unix_socket: {:?}}}", socket))
+                        .unwrap(),
+                ),
+                ..Default::default()
+            };
+            let ping = ping.create_function(&host).unwrap();
+            assert_eq!(ping.call(&HashMap::new()).unwrap(), Value::from("pong"));
+            assert!(
+                server.join().unwrap()[0].starts_with("GET /ping HTTP/1.1\r\nHost: localhost\r\n")
+            );
+        }
+    }
+}
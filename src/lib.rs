@@ -1,9 +1,17 @@
 extern crate jintemplify_plugin;
 mod ast;
+pub mod cache;
 mod command;
 pub mod function;
+pub mod host;
+mod http_plugin;
 pub mod plugin;
+mod process_plugin;
 pub mod wasm_plugin;
-pub use ast::{ExecutableFunction, FilterDeclaration, FunctionDeclaration};
+pub use ast::{Declaration, ExecutableFunction};
 mod render;
+mod rhai_plugin;
+mod session;
 mod shell_plugin;
+mod signature;
+mod template_plugin;
@@ -9,8 +9,15 @@ use std::fs;
 use std::path::Path;
 use tera::{Context, Tera};
 mod ast;
+use ast::GlobalStage;
+mod cache;
+use cache::Cache;
+mod catalog;
+use catalog::CatalogFormat;
 mod plugin;
-use plugin::Plugin;
+use plugin::{apply_globals, Plugin};
+mod process_plugin;
+use process_plugin::Processes;
 mod error;
 use error::panic_hook;
 mod filter;
@@ -18,7 +25,14 @@ mod render;
 use render::{render_template, render_variables};
 mod command;
 mod function;
+mod host;
+mod http_plugin;
+mod rhai_plugin;
+use host::HostEnv;
+mod session;
 mod shell_plugin;
+mod signature;
+mod template_plugin;
 mod wasm_plugin;
 use anyhow::Context as _Context;
 struct Args {
@@ -29,6 +43,20 @@ struct Args {
     output_file: Option<String>,
     disable_builtin_functions: bool,
     include_dirs: Vec<(String, Option<String>)>,
+    no_cache: bool,
+    clear_cache: bool,
+    allow_override: bool,
+    /// `plugins list` (no name) or `plugins describe NAME`.
+    catalog: Option<(Option<String>, CatalogFormat)>,
+}
+
+fn format_arg() -> Arg {
+    Arg::new("format")
+        .long("format")
+        .value_parser(["text", "markdown", "json"])
+        .default_value("text")
+        .action(ArgAction::Set)
+        .help("Output format")
 }
 
 fn parse_arguments() -> Args {
@@ -50,6 +78,7 @@ fn parse_arguments() -> Args {
                 .action(ArgAction::Append)
                 .help("Optional environment variables in the format key=default_value"),
         )
+        .subcommand_negates_reqs(true)
         .arg(
             Arg::new("template")
                 .short('t')
@@ -69,6 +98,7 @@ fn parse_arguments() -> Args {
             Arg::new("plugin")
                 .short('p')
                 .long("plugin")
+                .global(true)
                 .action(ArgAction::Set)
                 .help("Path to the plugin configuration: plugin.yaml"),
         )
@@ -91,8 +121,57 @@ fn parse_arguments() -> Args {
                 .action(ArgAction::Append)
                 .help("Include directory for templates. Format: /path/to/dir:alias or /path/to/dir. Use '{}' for direct naming without an alias."),
         )
+        .arg(
+            Arg::new("no_cache")
+                .long("no-cache")
+                .action(ArgAction::SetTrue)
+                .help("Runs every plugin call, ignoring the cache settings of plugins"),
+        )
+        .arg(
+            Arg::new("clear_cache")
+                .long("clear-cache")
+                .action(ArgAction::SetTrue)
+                .help("Removes cached plugin results before rendering"),
+        )
+        .arg(
+            Arg::new("allow_override")
+                .long("allow-override")
+                .action(ArgAction::SetTrue)
+                .help("Lets plugins replace built-in functions, filters and testers, or each other, with a warning"),
+        )
+        .subcommand(
+            Command::new("plugins")
+                .about("Lists the functions, filters and testers available to templates")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("list")
+                        .about("Lists everything registered, with the --plugin declarations")
+                        .arg(format_arg()),
+                )
+                .subcommand(
+                    Command::new("describe")
+                        .about("Shows the parameters and documentation of one entry")
+                        .arg(Arg::new("name").required(true).action(ArgAction::Set))
+                        .arg(format_arg()),
+                ),
+        )
         .get_matches();
 
+    let catalog = matches
+        .subcommand_matches("plugins")
+        .and_then(|plugins| plugins.subcommand())
+        .map(|(command, matches)| {
+            let format = match matches.get_one::<String>("format").map(String::as_str) {
+                Some("markdown") => CatalogFormat::Markdown,
+                Some("json") => CatalogFormat::Json,
+                _ => CatalogFormat::Text,
+            };
+            let name = (command == "describe")
+                .then(|| matches.get_one::<String>("name").cloned())
+                .flatten();
+            (name, format)
+        });
+
     let mut envs = HashMap::new();
     for (key, value) in env::vars() {
         envs.insert(key, value);
@@ -148,8 +227,8 @@ fn parse_arguments() -> Args {
         envs,
         template: matches
             .get_one::<String>("template")
-            .expect("required")
-            .to_string(),
+            .cloned()
+            .unwrap_or_default(),
         variables: matches
             .get_one::<String>("variables")
             .map(|s| s.to_string()),
@@ -159,6 +238,10 @@ fn parse_arguments() -> Args {
             .map(ToOwned::to_owned),
         disable_builtin_functions: matches.get_flag("disable_builtin_functions"),
         include_dirs,
+        no_cache: matches.get_flag("no_cache"),
+        clear_cache: matches.get_flag("clear_cache"),
+        allow_override: matches.get_flag("allow_override"),
+        catalog,
     }
 }
 
@@ -231,14 +314,52 @@ fn main() -> anyhow::Result<()> {
         .map(|(k, v)| (k.clone(), serde_yaml::Value::String(v.clone())))
         .collect();
     context.insert("vars", &global_vars);
+    let cache_dir = Cache::default_dir();
+    let cache = Cache::new(cache_dir.clone(), !args.no_cache);
+    if args.clear_cache {
+        cache
+            .clear()
+            .with_context(|| format!("Failed to clear cache {:?}", cache_dir))?;
+    }
+    let host = HostEnv::with_cache(cache);
+    // Shuts the plugin helper processes down when rendering is over.
+    let mut _processes = Processes::default();
+    let mut globals = Vec::new();
+
+    if let Some((name, format)) = &args.catalog {
+        let plugins = args
+            .plugin
+            .as_ref()
+            .map(|path| Plugin::load_from_file(path, &mut tera, &context).map(|p| (p, path)))
+            .transpose()?;
+        let entries = catalog::entries(
+            plugins.as_ref().map(|(p, path)| (p, path.as_str())),
+            !args.disable_builtin_functions,
+        );
+        print!("{}", catalog::render(&entries, name.as_deref(), *format)?);
+        return Ok(());
+    }
 
     if let Some(plugin_path) = &args.plugin {
         let plugins = Plugin::load_from_file(plugin_path, &mut tera, &context)?;
+        catalog::check_collisions(
+            &plugins,
+            plugin_path,
+            !args.disable_builtin_functions,
+            args.allow_override,
+        )?;
+        _processes = plugins.processes;
+        globals = plugins.globals.unwrap_or_default();
+        if !args.disable_builtin_functions {
+            if let Some(env_policy) = &plugins.env_policy {
+                function::register_shell(&mut tera, env_policy.clone(), host.clone());
+            }
+        }
         if let Some(functions) = plugins.functions {
             for func_decl in functions.into_iter() {
                 // Register Function Plugins
                 let name = &func_decl.name;
-                let func = func_decl.create()?;
+                let func = func_decl.create_function(&host)?;
                 tera.register_function(&name, func);
                 info!("register_function: {}", name);
             }
@@ -248,23 +369,54 @@ fn main() -> anyhow::Result<()> {
             for filter_decl in filters.into_iter() {
                 // Register Filter Plugins
                 let name = &filter_decl.name;
-                let filter = filter_decl.create()?;
+                let filter = filter_decl.create_filter(&host)?;
                 tera.register_filter(&name, filter);
                 info!("register_filter: {}", name);
             }
         }
+
+        if let Some(testers) = plugins.testers {
+            for tester_decl in testers.into_iter() {
+                // Register Tester Plugins
+                let name = &tester_decl.name;
+                let tester = tester_decl.create_tester(&host)?;
+                tera.register_tester(&name, tester);
+                info!("register_tester: {}", name);
+            }
+        }
     }
 
+    host.set_tera(&tera);
+    host.set_vars(&global_vars)?;
+
     // Render variables
+    if let Some(variables) = &args.variables {
+        host.set_target(variables, args.output_file.as_deref());
+    }
+    apply_globals(
+        &globals,
+        GlobalStage::BeforeVariables,
+        &host,
+        &mut global_vars,
+    )?;
+    context.insert("vars", &global_vars);
     let rendered_vars = render_variables(&mut tera, args.variables.as_deref(), &context)?;
 
     global_vars.extend(rendered_vars);
+    host.set_vars(&global_vars)?;
+    apply_globals(
+        &globals,
+        GlobalStage::AfterVariables,
+        &host,
+        &mut global_vars,
+    )?;
 
     let mut context = Context::new();
     context.insert("vars", &global_vars);
 
     // Render main template
     info!("try main: {}", args.template);
+    host.set_target(&args.template, args.output_file.as_deref());
     let rendered = render_template(&mut tera, &args.template, &context)?;
     match &args.output_file {
         Some(output_path) => {
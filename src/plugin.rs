@@ -1,82 +1,392 @@
 // src/plugin.rs
 use crate::ast::{
-    Executable, ExecutableFunction, FilterDeclaration, FunctionDeclaration, WasmDeclartion,
-    WasmFilter, WasmFunction,
+    CacheMode, CacheOptions, ContextItem, Declaration, EnvPolicy, Executable, ExecutableFunction,
+    ExportKind, GlobalDeclaration, GlobalStage, Param, ProcessConfig, SessionConfig, ShellOptions,
+    TemplatesSection, Wasm, WasmDeclartion, WasmFilter, WasmFunction, WasmModuleDeclaration,
+    WasmTester,
 };
+use crate::cache::{canonical, sha256_hex, CachedExecutable};
+use crate::host::{ContextExecutable, HostEnv};
+use crate::http_plugin::HttpExecutable;
+use crate::process_plugin::{ProcessExecutable, ProcessHandle, Processes};
 use crate::render::render_template;
-use crate::shell_plugin::{ShellCommand, ShellFilter, ShellFunction};
+use crate::rhai_plugin::{rhai_source, RhaiExecutable};
+use crate::session::Session;
+use crate::shell_plugin::{ShellCommand, ShellFilter, ShellFunction, ShellTester};
+use crate::signature::Signature;
+use crate::template_plugin::TemplateExecutable;
+use crate::wasm_plugin::{WasmExecutor, METADATA_EXPORT};
 use anyhow::{self, Context as _Context};
 use log::debug;
 use serde::Deserialize;
-use std::collections::HashMap;
-use tera::{Context, Filter, Function, Tera};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tera::{Context, Filter, Function, Tera, Test};
 
-impl FunctionDeclaration {
-    pub fn create(&self) -> anyhow::Result<ExecutableFunction> {
+/// Identifies a declaration's code and configuration, including the contents
+/// of its wasm module or script file, for the result cache. `backend`
+/// describes the other backends.
+fn fingerprint(
+    name: &str,
+    script: &Option<String>,
+    params: &Option<Vec<Param>>,
+    env: &Option<HashMap<String, String>>,
+    options: &ShellOptions,
+    wasm: &Option<Wasm>,
+    backend: impl serde::Serialize,
+) -> anyhow::Result<String> {
+    let module = match wasm {
+        Some(wasm) => Some(sha256_hex(
+            &std::fs::read(&wasm.path)
+                .with_context(|| format!("Failed to read wasm module {}", wasm.path))?,
+        )),
+        None => None,
+    };
+    let script_file = options
+        .script_file
+        .as_ref()
+        .and_then(|path| std::fs::read(path).ok())
+        .map(|content| sha256_hex(&content));
+    let env: Option<BTreeMap<_, _>> = env.as_ref().map(|env| env.iter().collect());
+    let source = serde_json::to_vec(&(
+        name,
+        script,
+        params,
+        env,
+        options,
+        wasm,
+        module,
+        script_file,
+        backend,
+    ))?;
+    Ok(sha256_hex(&source))
+}
+
+/// Wraps `executor` in the result cache when the declaration opts into it.
+/// `env_policy` is that of a backend running a child process.
+fn cached(
+    executor: Box<dyn Executable>,
+    caching: &CacheOptions,
+    env_policy: Option<&EnvPolicy>,
+    host: &HostEnv,
+    fingerprint: impl FnOnce() -> anyhow::Result<String>,
+) -> anyhow::Result<Box<dyn Executable>> {
+    if caching.cache == CacheMode::None {
+        return Ok(executor);
+    }
+    Ok(Box::new(CachedExecutable {
+        executor,
+        cache: host.cache().clone(),
+        options: *caching,
+        fingerprint: fingerprint()?,
+        env_policy: env_policy.cloned(),
+        host: host.clone(),
+    }))
+}
+
+/// Wraps `executor` so that it receives the render context the declaration
+/// lists in `context:`.
+fn with_context(
+    executor: Box<dyn Executable>,
+    items: &[ContextItem],
+    host: &HostEnv,
+) -> Box<dyn Executable> {
+    if items.is_empty() {
+        return executor;
+    }
+    Box::new(ContextExecutable {
+        executor,
+        items: items.to_vec(),
+        host: host.clone(),
+    })
+}
+
+/// Builds the shell backend when the declaration has a `script`,
+/// `script_file` or `exec`.
+fn shell_command(
+    name: &str,
+    script: &Option<String>,
+    params: &Option<Vec<Param>>,
+    env: &Option<HashMap<String, String>>,
+    options: &ShellOptions,
+    host: &HostEnv,
+) -> anyhow::Result<Option<ShellCommand>> {
+    let script = match (script, &options.script_file) {
+        (Some(_), Some(_)) => {
+            anyhow::bail!("'{}': script and script_file cannot both be set", name)
+        }
+        (Some(script), None) => script.clone(),
+        (None, Some(path)) => std::fs::read_to_string(path)
+            .with_context(|| format!("'{}': Failed to read script file {}", name, path))?,
+        (None, None) if options.exec.is_some() => String::new(),
+        (None, None) => return Ok(None),
+    };
+    let mut options = options.clone();
+    if options.session {
+        if options.exec.is_some() || options.interpreter.is_some() {
+            anyhow::bail!(
+                "'{}': exec and interpreter cannot be used with session",
+                name
+            );
+        }
+        options
+            .session_handle
+            .get_or_insert_with(|| Session::new(SessionConfig::default()));
+    }
+    Ok(Some(ShellCommand {
+        name: name.to_string(),
+        script,
+        params: params.clone(),
+        env: env.clone(),
+        options,
+        host: host.clone(),
+    }))
+}
+
+impl Declaration {
+    /// The backends the declaration sets, of which there must be exactly one.
+    pub fn backends(&self) -> Vec<&'static str> {
+        [
+            ("wasm", self.wasm.is_some()),
+            ("template", self.template.is_some()),
+            ("http", self.http.is_some()),
+            ("process", self.process.is_some()),
+            (
+                "rhai",
+                self.rhai.rhai.is_some() || self.rhai.rhai_file.is_some(),
+            ),
+            ("exec", self.shell.exec.is_some()),
+            (
+                "script",
+                self.script.is_some() || self.shell.script_file.is_some(),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(backend, set)| set.then_some(backend))
+        .collect()
+    }
+
+    /// Builds the executor of the declaration's backend for `kind`, wrapped
+    /// in the result cache and the render context it asks for.
+    fn build(&self, kind: ExportKind, host: &HostEnv) -> anyhow::Result<Box<dyn Executable>> {
+        match self.backends().as_slice() {
+            [_] => {}
+            [] => anyhow::bail!(
+                "'{}': Neither wasm, template, http, process, rhai nor script configurations were provided",
+                self.name
+            ),
+            backends => anyhow::bail!(
+                "'{}': only one backend can be set, found {}",
+                self.name,
+                backends.join(", ")
+            ),
+        }
+        let rhai = rhai_source(&self.name, &self.rhai)?;
         let executor: Box<dyn Executable> = if let Some(wasm_config) = &self.wasm {
-            Box::new(WasmFunction {
-                decl: WasmDeclartion {
-                    wasm: wasm_config.clone(),
-                    params: self.params.clone(),
-                },
-            })
+            let decl = WasmDeclartion {
+                wasm: wasm_config.clone(),
+                params: self.params.clone(),
+                name: self.name.clone(),
+                host: host.clone(),
+            };
+            match kind {
+                ExportKind::Function => Box::new(WasmFunction { decl }),
+                ExportKind::Filter => Box::new(WasmFilter { decl }),
+                ExportKind::Tester => Box::new(WasmTester { decl }),
+            }
+        } else if let Some(template) = &self.template {
+            Box::new(TemplateExecutable::new(
+                &self.name,
+                kind,
+                template,
+                self.shell.output,
+                host,
+            )?)
+        } else if let Some(http) = &self.http {
+            Box::new(HttpExecutable::new(
+                &self.name,
+                kind,
+                http,
+                &self.shell,
+                host,
+            )?)
+        } else if let Some(process) = &self.process {
+            Box::new(ProcessExecutable::new(&self.name, kind, process, host))
+        } else if let Some(executor) = RhaiExecutable::new(
+            &self.name,
+            &self.rhai,
+            rhai.clone(),
+            &self.params,
+            self.shell.timeout,
+        )? {
+            Box::new(executor)
         } else {
-            Box::new(ShellFunction {
-                command: ShellCommand {
-                    script: self.script.as_ref().unwrap().clone(),
-                    params: self.params.clone(),
-                    env: self.env.clone(),
-                },
-            })
+            let command = shell_command(
+                &self.name,
+                &self.script,
+                &self.params,
+                &self.env,
+                &self.shell,
+                host,
+            )?
+            .expect("the declaration has a shell backend");
+            match kind {
+                ExportKind::Function => Box::new(ShellFunction { command }),
+                ExportKind::Filter => Box::new(ShellFilter { command }),
+                ExportKind::Tester => Box::new(ShellTester { command }),
+            }
         };
+        let env_policy = if let Some(process) = &self.process {
+            Some(&process.env_policy)
+        } else if self.wasm.is_none()
+            && self.template.is_none()
+            && self.http.is_none()
+            && rhai.is_none()
+        {
+            Some(&self.shell.env_policy)
+        } else {
+            None
+        };
+        let executor = cached(executor, &self.caching, env_policy, host, || {
+            fingerprint(
+                &self.name,
+                &self.script,
+                &self.params,
+                &self.env,
+                &self.shell,
+                &self.wasm,
+                (&self.process, &rhai, &self.template, &self.http),
+            )
+        })?;
+        Ok(with_context(executor, &self.context, host))
+    }
 
+    pub fn create_function(&self, host: &HostEnv) -> anyhow::Result<ExecutableFunction> {
         Ok(ExecutableFunction {
-            executor,
+            executor: self.build(ExportKind::Function, host)?,
             name: self.name.clone(),
+            signature: Signature::new(&self.name, &self.params, false)?,
+        })
+    }
+
+    pub fn create_filter(&self, host: &HostEnv) -> anyhow::Result<ExecutableFilter> {
+        Ok(ExecutableFilter {
+            executor: self.build(ExportKind::Filter, host)?,
+            name: self.name.clone(),
+            signature: Signature::new(&self.name, &self.params, true)?,
+        })
+    }
+
+    pub fn create_tester(&self, host: &HostEnv) -> anyhow::Result<ExecutableTester> {
+        Ok(ExecutableTester {
+            executor: self.build(ExportKind::Tester, host)?,
+            name: self.name.clone(),
+            signature: Signature::new(&self.name, &self.params, false)?,
         })
     }
 }
+
 impl Function for ExecutableFunction {
     fn call(&self, args: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
         debug!("function call: {}, params={:?}", self.name, args);
-        let result = self.executor.execute(args, None)?;
+        let args = self.signature.validate(args)?;
+        let result = self.executor.execute(&args, None)?;
         Ok(result)
     }
 }
 
-impl FilterDeclaration {
-    pub fn create(&self) -> anyhow::Result<ExecutableFilter> {
-        let executor: Box<dyn Executable> = match (&self.wasm, &self.script) {
-            (Some(wasm_config), _) => Box::new(WasmFilter {
-                decl: WasmDeclartion {
-                    wasm: wasm_config.clone(),
-                    params: self.params.clone(),
-                },
-            }),
-            (None, Some(script)) => Box::new(ShellFilter {
-                command: ShellCommand {
-                    script: script.clone(),
-                    params: self.params.clone(),
-                    env: self.env.clone(),
-                },
-            }),
-            (None, None) => {
-                return Err(anyhow::anyhow!(
-                    "Neither wasm nor script configurations were provided"
-                ));
+/// `base` with each item of `top` replacing the one of the same name, or
+/// appended when there is none.
+fn overlay<T>(
+    base: Option<Vec<T>>,
+    top: Option<Vec<T>>,
+    name: impl Fn(&T) -> &str,
+) -> Option<Vec<T>> {
+    let Some(top) = top else {
+        return base;
+    };
+    let mut merged = base.unwrap_or_default();
+    for item in top {
+        match merged
+            .iter()
+            .position(|existing| name(existing) == name(&item))
+        {
+            Some(index) => merged[index] = item,
+            None => merged.push(item),
+        }
+    }
+    Some(merged)
+}
+
+/// Merges `source` into `target`, key by key where both are objects.
+fn deep_merge(target: &mut serde_yaml::Value, source: serde_yaml::Value) {
+    match (target, source) {
+        (serde_yaml::Value::Mapping(target), serde_yaml::Value::Mapping(source)) => {
+            for (key, value) in source {
+                match target.get_mut(&key) {
+                    Some(existing) => deep_merge(existing, value),
+                    None => {
+                        target.insert(key, value);
+                    }
+                }
             }
+        }
+        (target, source) => *target = source,
+    }
+}
+
+impl GlobalDeclaration {
+    /// Runs the provider and adds its result to `vars`.
+    pub fn apply(
+        &self,
+        host: &HostEnv,
+        vars: &mut HashMap<String, serde_yaml::Value>,
+    ) -> anyhow::Result<()> {
+        let name = &self.provider.name;
+        let value = self
+            .provider
+            .create_function(host)?
+            .call(&HashMap::new())
+            .with_context(|| format!("Failed to evaluate global '{}'", name))?;
+        if !self.merge {
+            vars.insert(name.clone(), serde_yaml::to_value(value)?);
+            return Ok(());
+        }
+        let tera::Value::Object(map) = value else {
+            anyhow::bail!("Global '{}' must return an object to be merged", name);
         };
+        for (key, value) in map {
+            let value = serde_yaml::to_value(value)?;
+            match vars.get_mut(&key) {
+                Some(existing) => deep_merge(existing, value),
+                None => {
+                    vars.insert(key, value);
+                }
+            }
+        }
+        Ok(())
+    }
+}
 
-        Ok(ExecutableFilter {
-            executor,
-            name: self.name.clone(),
-        })
+/// Runs the `globals:` entries of `stage` in order; each one sees the values
+/// of the previous ones in `vars`.
+pub fn apply_globals(
+    globals: &[GlobalDeclaration],
+    stage: GlobalStage,
+    host: &HostEnv,
+    vars: &mut HashMap<String, serde_yaml::Value>,
+) -> anyhow::Result<()> {
+    for global in globals.iter().filter(|global| global.stage == stage) {
+        global.apply(host, vars)?;
+        host.set_vars(vars)?;
     }
+    Ok(())
 }
 
 pub struct ExecutableFilter {
     executor: Box<dyn Executable>,
     name: String,
+    signature: Signature,
 }
 
 impl Filter for ExecutableFilter {
@@ -89,15 +399,129 @@ impl Filter for ExecutableFilter {
             "filter call: {}, params={:?}, value={:?}",
             self.name, args, value
         );
-        let result = self.executor.execute(args, Some(value))?;
+        let args = self.signature.validate(args)?;
+        let result = self.executor.execute(&args, Some(value))?;
         Ok(result)
     }
 }
 
-#[derive(Debug, Deserialize)]
+pub struct ExecutableTester {
+    executor: Box<dyn Executable>,
+    name: String,
+    /// Its positional arguments are matched to the declared parameters.
+    signature: Signature,
+}
+
+impl Test for ExecutableTester {
+    fn test(&self, value: Option<&tera::Value>, args: &[tera::Value]) -> tera::Result<bool> {
+        debug!(
+            "tester call: {}, params={:?}, value={:?}",
+            self.name, args, value
+        );
+        let params = self.signature.positional();
+        if args.len() > params.len() {
+            return Err(tera::Error::msg(format!(
+                "Tester '{}' takes {} argument(s) but {} were given",
+                self.name,
+                params.len(),
+                args.len()
+            )));
+        }
+        let args: HashMap<String, tera::Value> =
+            params.into_iter().zip(args.iter().cloned()).collect();
+        let args = self.signature.validate(&args)?;
+        match self.executor.execute(&args, value)? {
+            tera::Value::Bool(b) => Ok(b),
+            other => Err(tera::Error::msg(format!(
+                "Tester '{}' must produce a boolean, got {}",
+                self.name, other
+            ))),
+        }
+    }
+}
+
+impl WasmModuleDeclaration {
+    fn is_selected(&self, name: &str) -> bool {
+        let included = match &self.include {
+            Some(include) => include.iter().any(|n| n == name),
+            None => true,
+        };
+        let excluded = match &self.exclude {
+            Some(exclude) => exclude.iter().any(|n| n == name),
+            None => false,
+        };
+        included && !excluded
+    }
+
+    /// Reads the module's metadata export and declares every selected export.
+    pub fn discover(&self) -> anyhow::Result<Vec<(ExportKind, Declaration)>> {
+        let decl = WasmDeclartion {
+            wasm: Wasm {
+                path: self.wasm_module.clone(),
+                import: METADATA_EXPORT.to_string(),
+                permissions: None,
+            },
+            params: None,
+            name: self.wasm_module.clone(),
+            host: HostEnv::default(),
+        };
+        let metadata = WasmExecutor::new(&decl)
+            .and_then(|mut executor| executor.metadata())
+            .with_context(|| format!("Failed to discover exports of {}", self.wasm_module))?;
+
+        let mut declarations = Vec::new();
+        for export in metadata.exports {
+            if !self.is_selected(&export.name) {
+                debug!("{}: skip export {}", self.wasm_module, export.name);
+                continue;
+            }
+            let decl = Declaration {
+                name: format!("{}{}", self.prefix.as_deref().unwrap_or(""), export.name),
+                params: export.params,
+                description: export.description,
+                wasm: Some(Wasm {
+                    path: self.wasm_module.clone(),
+                    import: export.name,
+                    permissions: self.permissions.clone(),
+                }),
+                ..Default::default()
+            };
+            declarations.push((export.kind, decl));
+        }
+        Ok(declarations)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
 pub struct Plugin {
-    pub functions: Option<Vec<FunctionDeclaration>>,
-    pub filters: Option<Vec<FilterDeclaration>>,
+    /// Plugin files whose declarations this one inherits, relative to it.
+    pub imports: Option<Vec<String>>,
+    /// Names of inherited declarations to leave out.
+    pub disable: Option<Vec<String>>,
+    /// Prepended with `_` to the names of the file's functions, filters and
+    /// testers.
+    pub namespace: Option<String>,
+    pub functions: Option<Vec<Declaration>>,
+    pub filters: Option<Vec<Declaration>>,
+    pub testers: Option<Vec<Declaration>>,
+    pub modules: Option<Vec<WasmModuleDeclaration>>,
+    /// Values added to `vars` before rendering.
+    pub globals: Option<Vec<GlobalDeclaration>>,
+    pub templates: Option<TemplatesSection>,
+    pub session: Option<SessionConfig>,
+    /// Defaults for the environment policy of every declaration, the session
+    /// and the `shell()` builtin.
+    pub env_policy: Option<EnvPolicy>,
+    /// The helpers started by `process` declarations.
+    #[serde(skip)]
+    pub processes: Processes,
+    /// Names declared twice in one file or by two imported files, reported
+    /// by `catalog::check_collisions`.
+    #[serde(skip)]
+    pub collisions: Vec<String>,
+    /// The names and bodies of `templates:`, those of imported files first.
+    #[serde(skip)]
+    template_bodies: Vec<(String, String)>,
 }
 
 impl Plugin {
@@ -106,30 +530,336 @@ impl Plugin {
         tera: &mut Tera,
         context: &Context,
     ) -> anyhow::Result<Plugin> {
-        let content = render_template(tera, path, context)?;
-        serde_yaml::from_str(&content).context("Failed to parse plugin file")
+        let mut plugin = Plugin::load_document(Path::new(path), tera, context, &mut Vec::new())?;
+        let templates = std::mem::take(&mut plugin.template_bodies);
+        if !templates.is_empty() {
+            tera.add_raw_templates(templates)
+                .context("Failed to add plugin templates")?;
+        }
+        plugin.attach_session();
+        plugin.attach_processes()?;
+        Ok(plugin)
+    }
+
+    /// Reads one plugin file on top of the files it imports. `chain` holds
+    /// the files being loaded, to report import cycles.
+    fn load_document(
+        path: &Path,
+        tera: &mut Tera,
+        context: &Context,
+        chain: &mut Vec<PathBuf>,
+    ) -> anyhow::Result<Plugin> {
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("Failed to read plugin file {:?}", path))?;
+        if let Some(start) = chain.iter().position(|loading| *loading == canonical) {
+            let cycle = chain[start..]
+                .iter()
+                .chain(std::iter::once(&canonical))
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            anyhow::bail!("Plugin import cycle: {}", cycle);
+        }
+
+        let display = path.to_string_lossy();
+        let content = render_template(tera, &display, context)?;
+        let mut plugin: Plugin = serde_yaml::from_str(&content)
+            .with_context(|| format!("Failed to parse plugin file {}", display))?;
+        plugin.template_bodies = plugin.read_templates(&display)?;
+        plugin.discover_modules()?;
+        plugin.apply_namespace();
+        plugin.collisions = plugin.duplicates(&display);
+
+        let imports = plugin.imports.take().unwrap_or_default();
+        if imports.is_empty() {
+            if plugin.disable.is_some() {
+                anyhow::bail!("{}: disable needs imports", display);
+            }
+            return Ok(Plugin::default().overlay(plugin));
+        }
+        chain.push(canonical);
+        let base = path.parent().unwrap_or(Path::new(""));
+        let disabled = plugin.disable.take().unwrap_or_default();
+        let mut declared_by: HashMap<(ExportKind, String), PathBuf> = HashMap::new();
+        let mut inherited = Plugin::default();
+        for import in imports.iter() {
+            let import_path = base.join(import);
+            let imported = Plugin::load_document(&import_path, tera, context, chain)
+                .with_context(|| format!("Failed to import {} from {}", import, display))?;
+            for (kind, decl) in imported.declarations() {
+                if disabled.contains(&decl.name) {
+                    continue;
+                }
+                let key = (kind, decl.name.clone());
+                if let Some(other) = declared_by.insert(key, import_path.clone()) {
+                    inherited.collisions.push(format!(
+                        "{} '{}' is declared by both {} and {}",
+                        kind.name(),
+                        decl.name,
+                        other.display(),
+                        import_path.display()
+                    ));
+                }
+            }
+            inherited = inherited.overlay(imported);
+        }
+        chain.pop();
+        inherited
+            .disable(disabled)
+            .with_context(|| format!("{}: invalid disable", display))?;
+        Ok(inherited.overlay(plugin))
+    }
+
+    /// The file's functions, filters and testers with their kind.
+    pub fn declarations(&self) -> impl Iterator<Item = (ExportKind, &Declaration)> {
+        [
+            (ExportKind::Function, &self.functions),
+            (ExportKind::Filter, &self.filters),
+            (ExportKind::Tester, &self.testers),
+        ]
+        .into_iter()
+        .flat_map(|(kind, declarations)| declarations.iter().flatten().map(move |d| (kind, d)))
+    }
+
+    /// The declarations of the file that reuse the name of an earlier one of
+    /// the same kind, which they would silently replace.
+    fn duplicates(&self, path: &str) -> Vec<String> {
+        let mut seen = HashSet::new();
+        self.declarations()
+            .filter(|(kind, decl)| !seen.insert((*kind, decl.name.as_str())))
+            .map(|(kind, decl)| {
+                format!(
+                    "{} '{}' is declared more than once in {}",
+                    kind.name(),
+                    decl.name,
+                    path
+                )
+            })
+            .collect()
+    }
+
+    /// The file's functions, filters and testers.
+    fn declarations_mut(&mut self) -> impl Iterator<Item = &mut Declaration> {
+        self.functions
+            .iter_mut()
+            .chain(self.filters.iter_mut())
+            .chain(self.testers.iter_mut())
+            .flatten()
+    }
+
+    /// The file's declarations and the providers of its globals.
+    fn declarations_and_globals_mut(&mut self) -> impl Iterator<Item = &mut Declaration> {
+        let globals = self
+            .globals
+            .iter_mut()
+            .flatten()
+            .map(|global| &mut global.provider);
+        self.functions
+            .iter_mut()
+            .chain(self.filters.iter_mut())
+            .chain(self.testers.iter_mut())
+            .flatten()
+            .chain(globals)
+    }
+
+    /// Prefixes the file's function, filter and tester names with its
+    /// namespace. Process helpers keep being called by the declared name.
+    fn apply_namespace(&mut self) {
+        let Some(namespace) = self.namespace.take() else {
+            return;
+        };
+        let rename = |name: &mut String, process: Option<&mut ProcessConfig>| {
+            if let Some(process) = process {
+                process.method.get_or_insert_with(|| name.clone());
+            }
+            *name = format!("{}_{}", namespace, name);
+        };
+        for decl in self.declarations_mut() {
+            rename(&mut decl.name, decl.process.as_mut());
+        }
+    }
+
+    /// Removes the declarations named in `names`, each of which must exist.
+    fn disable(&mut self, names: Vec<String>) -> anyhow::Result<()> {
+        for name in names.iter() {
+            let mut found = false;
+            let mut keep = |declared: &str| {
+                let disabled = declared == name;
+                found |= disabled;
+                !disabled
+            };
+            if let Some(functions) = &mut self.functions {
+                functions.retain(|decl| keep(&decl.name));
+            }
+            if let Some(filters) = &mut self.filters {
+                filters.retain(|decl| keep(&decl.name));
+            }
+            if let Some(testers) = &mut self.testers {
+                testers.retain(|decl| keep(&decl.name));
+            }
+            if let Some(globals) = &mut self.globals {
+                globals.retain(|global| keep(&global.provider.name));
+            }
+            if !found {
+                anyhow::bail!("'{}' is not declared by any imported file", name);
+            }
+        }
+        Ok(())
+    }
+
+    /// `self` with the declarations of `top` added, replacing those of the
+    /// same name and kind. Other settings of `top` win.
+    fn overlay(self, top: Plugin) -> Plugin {
+        let env_policy = match (self.env_policy, top.env_policy) {
+            (Some(base), Some(top)) => Some(top.or(&base)),
+            (base, top) => top.or(base),
+        };
+        Plugin {
+            imports: None,
+            disable: None,
+            namespace: None,
+            functions: overlay(self.functions, top.functions, |decl| &decl.name),
+            filters: overlay(self.filters, top.filters, |decl| &decl.name),
+            testers: overlay(self.testers, top.testers, |decl| &decl.name),
+            modules: overlay(self.modules, top.modules, |module| &module.wasm_module),
+            globals: overlay(self.globals, top.globals, |global| &global.provider.name),
+            templates: None,
+            session: top.session.or(self.session),
+            env_policy,
+            processes: Processes::default(),
+            collisions: [self.collisions, top.collisions].concat(),
+            template_bodies: [self.template_bodies, top.template_bodies].concat(),
+        }
+    }
+
+    /// Applies the file's `env_policy` to every declaration and hands one
+    /// shared session to those with `session: true`.
+    fn attach_session(&mut self) {
+        let global = self.env_policy.clone().unwrap_or_default();
+        let mut config = self.session.clone().unwrap_or_default();
+        config.env_policy = config.env_policy.or(&global);
+        let session = Session::new(config);
+        let options = self
+            .declarations_and_globals_mut()
+            .map(|decl| &mut decl.shell);
+        for options in options {
+            options.env_policy = options.env_policy.or(&global);
+            if options.session {
+                options.session_handle = Some(session.clone());
+            }
+        }
+    }
+
+    /// Gives the `process` declarations that run the same helper, with the
+    /// same settings, one shared handle.
+    fn attach_processes(&mut self) -> anyhow::Result<()> {
+        let global = self.env_policy.clone().unwrap_or_default();
+        let mut handles: HashMap<String, ProcessHandle> = HashMap::new();
+        let mut started = Vec::new();
+        let configs = self
+            .declarations_and_globals_mut()
+            .filter_map(|decl| decl.process.as_mut());
+        for config in configs {
+            config.env_policy = config.env_policy.or(&global);
+            let key = canonical(&serde_json::to_value(ProcessConfig {
+                method: None,
+                timeout: None,
+                ..config.clone()
+            })?)
+            .to_string();
+            let handle = handles.entry(key).or_insert_with(|| {
+                let handle = ProcessHandle::new(config);
+                started.push(handle.clone());
+                handle
+            });
+            config.handle = Some(handle.clone());
+        }
+        self.processes.0.extend(started);
+        Ok(())
+    }
+
+    /// Reads the macro libraries and partials of `templates:`. They are added
+    /// to Tera together with those of the imported files once the whole file
+    /// is loaded, so that they can extend and import each other.
+    fn read_templates(&self, path: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let Some(section) = &self.templates else {
+            return Ok(Vec::new());
+        };
+        let base = Path::new(path).parent().unwrap_or(Path::new(""));
+        let mut templates = Vec::new();
+        for file in section.files.iter() {
+            let (name, body) = match (&file.body, &file.path) {
+                (Some(body), None) => {
+                    let name = file
+                        .name
+                        .clone()
+                        .ok_or_else(|| anyhow::anyhow!("An inline template needs a name"))?;
+                    (name, body.clone())
+                }
+                (None, Some(relative)) => {
+                    let full = base.join(relative);
+                    let body = std::fs::read_to_string(&full)
+                        .with_context(|| format!("Failed to read template file {:?}", full))?;
+                    let name = match &file.name {
+                        Some(name) => name.clone(),
+                        None => Path::new(relative)
+                            .file_name()
+                            .and_then(|name| name.to_str())
+                            .ok_or_else(|| anyhow::anyhow!("Invalid template path {}", relative))?
+                            .to_string(),
+                    };
+                    (name, body)
+                }
+                _ => anyhow::bail!(
+                    "Template '{}' needs either a body or a path",
+                    file.name.as_deref().unwrap_or_default()
+                ),
+            };
+            let name = match &section.prefix {
+                Some(prefix) => format!("{}.{}", prefix, name),
+                None => name,
+            };
+            debug!("plugin template: {}", name);
+            templates.push((name, body));
+        }
+        Ok(templates)
+    }
+
+    /// Appends the declarations discovered from `modules` to `functions`,
+    /// `filters` and `testers`.
+    fn discover_modules(&mut self) -> anyhow::Result<()> {
+        for module in self.modules.iter().flatten() {
+            for (kind, decl) in module.discover()? {
+                let declarations = match kind {
+                    ExportKind::Function => &mut self.functions,
+                    ExportKind::Filter => &mut self.filters,
+                    ExportKind::Tester => &mut self.testers,
+                };
+                declarations.get_or_insert_with(Vec::new).push(decl);
+            }
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::ast::Param;
+    use crate::ast::{InputMode, OutputFormat, ParamMode, ResultMode};
     use tera::Value;
 
     #[test]
     fn test_plugin_function_call() {
-        let func_decl = FunctionDeclaration {
+        let func_decl = Declaration {
             name: "echo_test".to_string(),
             params: Some(vec![Param {
                 name: "msg".to_string(),
                 description: Some("Echoes a message".to_string()),
-                default: None,
+                ..Default::default()
             }]),
-            description: None,
-            env: None,
-            wasm: None,
-            script: Some("echo $(msg)".to_string()),
+            script: Some("echo \"$JT_PARAM_msg\"".to_string()),
+            ..Default::default()
         };
 
         let mut args = HashMap::new();
@@ -138,8 +868,902 @@ mod tests {
             Value::String("Hello, world!".to_string()),
         );
 
-        let func = func_decl.create().unwrap();
+        let func = func_decl.create_function(&HostEnv::default()).unwrap();
         let result = func.call(&args).unwrap();
         assert_eq!(result, Value::String("Hello, world!\n".to_string()));
     }
+
+    #[test]
+    fn test_shell_script_runs_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let script_file = dir.path().join("count.sh");
+        std::fs::write(&script_file, "echo run >> runs.txt\nwc -l < runs.txt\n").unwrap();
+        let shell = |options: ShellOptions| ShellOptions {
+            cwd: Some(dir.path().to_string_lossy().into_owned()),
+            output: OutputFormat::Trim,
+            ..options
+        };
+        let call = |decl: Declaration| {
+            decl.create_function(&HostEnv::default())
+                .unwrap()
+                .call(&HashMap::new())
+                .unwrap()
+        };
+
+        let shebang = Declaration {
+            name: "shebang".to_string(),
+            script: Some("#!/bin/sh\necho run >> runs.txt\nwc -l < runs.txt".to_string()),
+            shell: shell(ShellOptions::default()),
+            ..Default::default()
+        };
+        assert_eq!(call(shebang), Value::from("1"));
+
+        let from_file = Declaration {
+            name: "from_file".to_string(),
+            shell: shell(ShellOptions {
+                script_file: Some(script_file.to_string_lossy().into_owned()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(call(from_file), Value::from("2"));
+
+        let strict = Declaration {
+            name: "strict".to_string(),
+            script: Some("echo \"$UNSET_VARIABLE\"".to_string()),
+            shell: shell(ShellOptions {
+                interpreter: Some("sh -eu".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let err = strict
+            .create_function(&HostEnv::default())
+            .unwrap()
+            .call(&HashMap::new())
+            .unwrap_err();
+        assert!(format!("{:?}", err).contains("UNSET_VARIABLE"), "{:?}", err);
+    }
+
+    #[test]
+    fn test_shell_result_and_failure_policy() {
+        let decl = |script: &str, shell: ShellOptions| Declaration {
+            name: "status".to_string(),
+            script: Some(script.to_string()),
+            shell,
+            ..Default::default()
+        };
+        let call = |decl: Declaration| {
+            decl.create_function(&HostEnv::default())
+                .unwrap()
+                .call(&HashMap::new())
+        };
+        let script = "echo out; echo err >&2; exit 1";
+
+        let err = call(decl(script, ShellOptions::default())).unwrap_err();
+        assert!(format!("{:?}", err).contains("err"), "{:?}", err);
+
+        let full = ShellOptions {
+            result: ResultMode::Full,
+            output: OutputFormat::Trim,
+            allow_failure: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            call(decl(script, full)).unwrap(),
+            serde_json::json!({"stdout": "out", "stderr": "err\n", "status": 1})
+        );
+
+        let ok_codes = ShellOptions {
+            ok_codes: Some(vec![0, 1]),
+            ..Default::default()
+        };
+        assert_eq!(
+            call(decl(script, ok_codes.clone())).unwrap(),
+            Value::from("out\n")
+        );
+        assert!(call(decl("exit 2", ok_codes)).is_err());
+    }
+
+    #[test]
+    fn test_shell_session() {
+        let mut plugin: Plugin = serde_yaml::from_str(
+            r#"
+session:
+  setup: |
+    greet() { printf 'hello %s' "$1"; }
+functions:
+  - name: greet
+    session: true
+    params:
+      - name: who
+    script: greet "$1"
+  - name: pid
+    session: true
+    script: echo $$
+  - name: same_pid
+    session: true
+    script: echo $$
+"#,
+        )
+        .unwrap();
+        plugin.attach_session();
+        let host = HostEnv::default();
+        let functions: HashMap<_, _> = plugin
+            .functions
+            .unwrap()
+            .iter()
+            .map(|decl| (decl.name.clone(), decl.create_function(&host).unwrap()))
+            .collect();
+        let call = |name: &str, args: &[(&str, &str)]| {
+            let args = args
+                .iter()
+                .map(|(k, v)| (k.to_string(), Value::from(*v)))
+                .collect();
+            functions[name].call(&args).unwrap()
+        };
+
+        assert_eq!(
+            call("greet", &[("who", "it's me")]),
+            Value::from("hello it's me")
+        );
+        // The declarations share one interpreter.
+        assert_eq!(call("pid", &[]), call("same_pid", &[]));
+    }
+
+    #[test]
+    fn test_shell_env_policy() {
+        // Any variable of the test process will do; the environment is
+        // shared by every test, so it is read rather than set.
+        let (inherited, value) = std::env::vars()
+            .find(|(name, _)| {
+                name != "PATH"
+                    && !name.starts_with(|c: char| c.is_ascii_digit())
+                    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            })
+            .expect("the test process has environment variables");
+        let mut plugin: Plugin = serde_yaml::from_str(&format!(
+            r#"
+env_policy:
+  inherit: none
+  path: /usr/bin:/bin
+functions:
+  - name: clean
+    script: printf '%s %s' "${{{0}-unset}}" "$PATH"
+  - name: only
+    inherit: [{0}]
+    script: printf '%s' "${{{0}-unset}}"
+  - name: all
+    inherit: all
+    clear_env: false
+    script: printf '%s' "${{{0}-unset}}"
+  - name: token
+    env_from_vars:
+      TOKEN: registry.token
+    script: echo "$TOKEN"
+  - name: missing
+    env_from_vars:
+      TOKEN: registry.missing
+    script: echo "$TOKEN"
+"#,
+            inherited
+        ))
+        .unwrap();
+        plugin.attach_session();
+        let host = HostEnv::default();
+        host.set_vars(&serde_json::json!({"registry": {"token": "s3cret"}}))
+            .unwrap();
+        let functions: HashMap<_, _> = plugin
+            .functions
+            .unwrap()
+            .iter()
+            .map(|decl| (decl.name.clone(), decl.create_function(&host).unwrap()))
+            .collect();
+        let call = |name: &str| functions[name].call(&HashMap::new());
+
+        assert_eq!(call("clean").unwrap(), Value::from("unset /usr/bin:/bin"));
+        assert_eq!(call("only").unwrap(), Value::from(value.as_str()));
+        assert_eq!(call("all").unwrap(), Value::from(value));
+        assert_eq!(call("token").unwrap(), Value::from("s3cret\n"));
+        let err = call("missing").unwrap_err();
+        assert!(err.to_string().contains("registry.missing"), "{}", err);
+    }
+
+    #[test]
+    fn test_process_plugin() {
+        // A helper that keeps a counter between calls.
+        let helper = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/rpc_helper.sh");
+        let yaml = format!(
+            r#"
+functions:
+  - name: count
+    process: {{command: [sh, {helper:?}]}}
+  - name: count_again
+    process: {{command: [sh, {helper:?}], method: count}}
+filters:
+  - name: upper
+    process: {{command: [sh, {helper:?}]}}
+"#
+        );
+        let mut plugin: Plugin = serde_yaml::from_str(&yaml).unwrap();
+        plugin.attach_processes().unwrap();
+        assert_eq!(plugin.processes.0.len(), 1);
+        let host = HostEnv::default();
+        let functions: HashMap<_, _> = plugin
+            .functions
+            .take()
+            .unwrap()
+            .iter()
+            .map(|decl| (decl.name.clone(), decl.create_function(&host).unwrap()))
+            .collect();
+        let call = |name: &str| functions[name].call(&HashMap::new());
+
+        // One helper answers every declaration and keeps its state.
+        assert_eq!(call("count").unwrap(), Value::from(1));
+        assert_eq!(call("count_again").unwrap(), Value::from(2));
+        assert_eq!(call("count").unwrap(), Value::from(3));
+    }
+
+    #[test]
+    fn test_rhai_plugin() {
+        let plugin: Plugin = serde_yaml::from_str(
+            r#"
+functions:
+  - name: repeat
+    params:
+      - name: word
+        type: string
+      - name: times
+        type: int
+        default: 2
+    rhai: |
+      let words = [];
+      for i in 0..times { words.push(word); }
+      #{ joined: words.reduce(|sum, w| if sum == () { w } else { sum + "-" + w }), count: times }
+filters:
+  - name: total
+    rhai: input.reduce(|sum, n| sum + n, 0)
+testers:
+  - name: even
+    rhai: input % 2 == 0
+"#,
+        )
+        .unwrap();
+        let host = HostEnv::default();
+        let functions: HashMap<_, _> = plugin
+            .functions
+            .unwrap()
+            .iter()
+            .map(|decl| (decl.name.clone(), decl.create_function(&host).unwrap()))
+            .collect();
+
+        let args = [("word".to_string(), Value::from("ab"))].into();
+        assert_eq!(
+            functions["repeat"].call(&args).unwrap(),
+            serde_json::json!({"joined": "ab-ab", "count": 2})
+        );
+
+        let filter = plugin.filters.unwrap()[0].create_filter(&host).unwrap();
+        assert_eq!(
+            filter
+                .filter(&serde_json::json!([1, 2, 3]), &HashMap::new())
+                .unwrap(),
+            Value::from(6)
+        );
+        let tester = plugin.testers.unwrap()[0].create_tester(&host).unwrap();
+        assert!(tester.test(Some(&Value::from(4)), &[]).unwrap());
+        assert!(!tester.test(Some(&Value::from(3)), &[]).unwrap());
+    }
+
+    #[test]
+    fn test_template_plugin() {
+        let plugin: Plugin = serde_yaml::from_str(
+            r#"
+functions:
+  - name: image
+    params:
+      - name: name
+      - name: tag
+        default: latest
+    template: "{{ vars.registry }}/{{ name }}:{{ tag }}"
+filters:
+  - name: quote_lines
+    params:
+      - name: mark
+        default: ">"
+    template: "{% for line in input | split(pat='\n') %}{{ mark }} {{ line }}\n{% endfor %}"
+testers:
+  - name: local_image
+    template: "{% if input is starting_with(vars.registry) %}true{% else %}false{% endif %}"
+"#,
+        )
+        .unwrap();
+        let host = HostEnv::default();
+        let mut tera = Tera::default();
+        for decl in plugin.functions.iter().flatten() {
+            tera.register_function(&decl.name, decl.create_function(&host).unwrap());
+        }
+        for decl in plugin.filters.iter().flatten() {
+            tera.register_filter(&decl.name, decl.create_filter(&host).unwrap());
+        }
+        for decl in plugin.testers.iter().flatten() {
+            tera.register_tester(&decl.name, decl.create_tester(&host).unwrap());
+        }
+        host.set_tera(&tera);
+        host.set_vars(&serde_json::json!({"registry": "r.example"}))
+            .unwrap();
+
+        let mut context = Context::new();
+        context.insert("text", "a\nb");
+        let mut render = |template: &str| tera.render_str(template, &context).unwrap();
+        assert_eq!(render(r#"{{ image(name="api") }}"#), "r.example/api:latest");
+        assert_eq!(render(r#"{{ text | quote_lines }}"#), "> a\n> b\n");
+        assert_eq!(
+            render(r#"{% set img = image(name="api") %}{% if img is local_image %}yes{% endif %}"#),
+            "yes"
+        );
+    }
+
+    #[test]
+    fn test_render_context() {
+        let plugin: Plugin = serde_yaml::from_str(
+            r#"
+functions:
+  - name: target
+    context: [template_name, output_path]
+    output: json
+    script: echo "$JT_CONTEXT"
+  - name: from_file
+    context: [vars]
+    output: json
+    script: cat "$JT_CONTEXT_FILE"
+  - name: no_context
+    script: echo "[${JT_CONTEXT-unset}]"
+  - name: large
+    context: [vars]
+    script: echo "[${JT_CONTEXT-unset}]"
+  - name: region
+    context: [vars]
+    rhai: context.vars.region
+filters:
+  - name: banner
+    context: [template_name]
+    template: "{{ input }} ({{ context.template_name }})"
+"#,
+        )
+        .unwrap();
+        let host = HostEnv::default();
+        let mut tera = Tera::default();
+        for decl in plugin.functions.iter().flatten() {
+            tera.register_function(&decl.name, decl.create_function(&host).unwrap());
+        }
+        for decl in plugin.filters.iter().flatten() {
+            tera.register_filter(&decl.name, decl.create_filter(&host).unwrap());
+        }
+        host.set_tera(&tera);
+        host.set_vars(&serde_json::json!({"region": "eu"})).unwrap();
+        host.set_target("main.yaml.j2", Some("out.yaml"));
+
+        let mut render = |template: &str| tera.render_str(template, &Context::new()).unwrap();
+        assert_eq!(
+            render("{{ target() | json_encode() | safe }}"),
+            r#"{"output_path":"out.yaml","template_name":"main.yaml.j2"}"#
+        );
+        assert_eq!(render("{% set c = from_file() %}{{ c.vars.region }}"), "eu");
+        assert_eq!(render("{{ no_context() }}"), "[unset]\n");
+        assert_eq!(render("{{ region() }}"), "eu");
+        assert_eq!(render(r#"{{ "x" | banner }}"#), "x (main.yaml.j2)");
+
+        host.set_target("other.j2", None);
+        assert_eq!(
+            render("{% set c = target() %}{{ c.output_path | json_encode() }}"),
+            "null"
+        );
+        assert_eq!(render(r#"{{ "x" | banner }}"#), "x (other.j2)");
+
+        // Large contexts are only passed in the file.
+        let region = "x".repeat(crate::shell_plugin::MAX_ENV_CONTEXT);
+        host.set_vars(&serde_json::json!({ "region": region }))
+            .unwrap();
+        assert_eq!(
+            render("{% set c = from_file() %}{{ c.vars.region | length }}"),
+            crate::shell_plugin::MAX_ENV_CONTEXT.to_string()
+        );
+        assert_eq!(render("{{ large() }}"), "[unset]\n");
+    }
+
+    #[test]
+    fn test_globals() {
+        let plugin: Plugin = serde_yaml::from_str(
+            r#"
+globals:
+  - name: git_sha
+    output: trim
+    script: echo abc123
+  - name: build
+    merge: true
+    output: json
+    script: 'echo "{\"build\": {\"tools\": [\"make\"]}, \"arch\": \"x86_64\"}"'
+  - name: label
+    stage: after_variables
+    template: "{{ vars.name }}-{{ vars.git_sha }}"
+  - name: broken
+    stage: after_variables
+    merge: true
+    output: trim
+    script: echo not-an-object
+"#,
+        )
+        .unwrap();
+        let mut globals = plugin.globals.unwrap();
+        let broken = globals.pop().unwrap();
+        let host = HostEnv::default();
+        host.set_tera(&Tera::default());
+        let yaml = |text: &str| serde_yaml::from_str::<serde_yaml::Value>(text).unwrap();
+
+        let mut vars = HashMap::from([("build".to_string(), yaml("{version: 1}"))]);
+        apply_globals(&globals, GlobalStage::BeforeVariables, &host, &mut vars).unwrap();
+        assert_eq!(vars["git_sha"], yaml("abc123"));
+        assert_eq!(vars["build"], yaml("{version: 1, tools: [make]}"));
+        assert_eq!(vars["arch"], yaml("x86_64"));
+        assert!(!vars.contains_key("label"));
+
+        vars.insert("name".to_string(), yaml("app"));
+        host.set_vars(&vars).unwrap();
+        apply_globals(&globals, GlobalStage::AfterVariables, &host, &mut vars).unwrap();
+        assert_eq!(vars["label"], yaml("app-abc123"));
+
+        let err = broken.apply(&host, &mut vars).unwrap_err();
+        assert!(err.to_string().contains("must return an object"), "{}", err);
+    }
+
+    #[test]
+    fn test_plugin_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("snippets")).unwrap();
+        std::fs::write(
+            dir.path().join("snippets/stage.j2"),
+            "{% macro from(image, tag=\"latest\") %}FROM {{ image }}:{{ tag }}{% endmacro from %}",
+        )
+        .unwrap();
+        let plugin_path = dir.path().join("plugin.yaml");
+        std::fs::write(
+            &plugin_path,
+            r#"
+templates:
+  prefix: docker
+  files:
+    - path: snippets/stage.j2
+    - name: header
+      body: "{% raw %}# {{ vars.name }}{% endraw %}"
+"#,
+        )
+        .unwrap();
+
+        let mut tera = Tera::default();
+        Plugin::load_from_file(plugin_path.to_str().unwrap(), &mut tera, &Context::new()).unwrap();
+        let mut context = Context::new();
+        context.insert("vars", &serde_json::json!({"name": "api"}));
+        let rendered = tera
+            .render_str(
+                r#"{% import "docker.stage.j2" as stage %}{% include "docker.header" %}
+{{ stage::from(image="alpine") }}"#,
+                &context,
+            )
+            .unwrap();
+        assert_eq!(rendered, "# api\nFROM alpine:latest");
+
+        std::fs::write(
+            dir.path().join("site.yaml"),
+            r#"
+imports: [plugin.yaml]
+templates:
+  prefix: site
+  files:
+    - name: Dockerfile
+      body: "{% raw %}{% extends \"docker.base\" %}{% block stages %}{% include \"docker.header\" %}{% endblock %}{% endraw %}"
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            &plugin_path,
+            r#"
+templates:
+  prefix: docker
+  files:
+    - name: base
+      body: "{% raw %}{% block stages %}{% endblock %}\nCMD []{% endraw %}"
+    - name: header
+      body: "{% raw %}# {{ vars.name }}{% endraw %}"
+"#,
+        )
+        .unwrap();
+        let mut tera = Tera::default();
+        let site = dir.path().join("site.yaml");
+        Plugin::load_from_file(site.to_str().unwrap(), &mut tera, &Context::new()).unwrap();
+        assert_eq!(
+            tera.render("site.Dockerfile", &context).unwrap(),
+            "# api\nCMD []"
+        );
+
+        std::fs::write(&plugin_path, "templates:\n  files:\n    - name: missing\n").unwrap();
+        let err = Plugin::load_from_file(plugin_path.to_str().unwrap(), &mut tera, &Context::new())
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("either a body or a path"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_plugin_imports() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |path: &str, content: &str| {
+            let path = dir.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, content).unwrap();
+            path.to_str().unwrap().to_string()
+        };
+        write(
+            "common/plugin.yaml",
+            r#"
+functions:
+  - name: greet
+    script: echo common
+  - name: legacy
+    script: echo legacy
+filters:
+  - name: shout
+    script: echo "$JT_INPUT!"
+"#,
+        );
+        let team = write(
+            "team/plugin.yaml",
+            r#"
+imports: [../common/plugin.yaml]
+disable: [legacy]
+functions:
+  - name: greet
+    script: echo team
+  - name: extra
+    script: echo extra
+"#,
+        );
+
+        let mut tera = Tera::default();
+        let plugin = Plugin::load_from_file(&team, &mut tera, &Context::new()).unwrap();
+        let functions: Vec<_> = plugin
+            .functions
+            .iter()
+            .flatten()
+            .map(|decl| (decl.name.as_str(), decl.script.as_deref().unwrap()))
+            .collect();
+        assert_eq!(
+            functions,
+            vec![("greet", "echo team"), ("extra", "echo extra")]
+        );
+        assert_eq!(plugin.filters.unwrap()[0].name, "shout");
+        assert!(plugin.collisions.is_empty(), "{:?}", plugin.collisions);
+
+        write(
+            "common/other.yaml",
+            r#"
+functions:
+  - name: greet
+    script: echo other
+  - name: twice
+    script: echo 1
+  - name: twice
+    script: echo 2
+"#,
+        );
+        let both = write(
+            "team/both.yaml",
+            "imports: [../common/plugin.yaml, ../common/other.yaml]\n",
+        );
+        let plugin = Plugin::load_from_file(&both, &mut tera, &Context::new()).unwrap();
+        let common = dir.path().join("team/../common/plugin.yaml");
+        let other_import = dir.path().join("team/../common/other.yaml");
+        assert_eq!(
+            plugin.collisions,
+            vec![
+                format!(
+                    "function 'greet' is declared by both {} and {}",
+                    common.display(),
+                    other_import.display()
+                ),
+                format!(
+                    "function 'twice' is declared more than once in {}",
+                    other_import.display()
+                ),
+            ]
+        );
+        let twice: Vec<_> = plugin
+            .functions
+            .iter()
+            .flatten()
+            .filter(|decl| decl.name == "twice")
+            .collect();
+        assert_eq!(twice.len(), 1);
+        let disabled = write(
+            "team/disabled.yaml",
+            "imports: [../common/plugin.yaml, ../common/other.yaml]\ndisable: [greet]\n",
+        );
+        let plugin = Plugin::load_from_file(&disabled, &mut tera, &Context::new()).unwrap();
+        assert_eq!(plugin.collisions.len(), 1, "{:?}", plugin.collisions);
+
+        let unknown = write(
+            "team/unknown.yaml",
+            "imports: [../common/plugin.yaml]\ndisable: [nope]\n",
+        );
+        let err = Plugin::load_from_file(&unknown, &mut tera, &Context::new()).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("'nope' is not declared"),
+            "{:#}",
+            err
+        );
+
+        write("cycle/a.yaml", "imports: [b.yaml]\n");
+        write("cycle/b.yaml", "imports: [c.yaml]\n");
+        write("cycle/c.yaml", "imports: [b.yaml]\n");
+        let err = Plugin::load_from_file(
+            dir.path().join("cycle/a.yaml").to_str().unwrap(),
+            &mut tera,
+            &Context::new(),
+        )
+        .unwrap_err();
+        let message = format!("{:#}", err);
+        let root = dir.path().canonicalize().unwrap();
+        let chain = format!(
+            "Plugin import cycle: {0}/cycle/b.yaml -> {0}/cycle/c.yaml -> {0}/cycle/b.yaml",
+            root.display()
+        );
+        assert!(message.contains(&chain), "{}", message);
+    }
+
+    #[test]
+    fn test_plugin_namespace() {
+        let dir = tempfile::tempdir().unwrap();
+        let common = dir.path().join("k8s.yaml");
+        std::fs::write(
+            &common,
+            r#"
+namespace: k8s
+functions:
+  - name: image_tag
+    process: {command: [k8s-helper]}
+  - name: context
+    script: echo common
+filters:
+  - name: labels
+    script: echo "$JT_INPUT"
+"#,
+        )
+        .unwrap();
+        let team = dir.path().join("team.yaml");
+        std::fs::write(
+            &team,
+            "imports: [k8s.yaml]\nfunctions:\n  - name: k8s_context\n    script: echo team\n",
+        )
+        .unwrap();
+
+        let mut tera = Tera::default();
+        let plugin =
+            Plugin::load_from_file(team.to_str().unwrap(), &mut tera, &Context::new()).unwrap();
+        let functions = plugin.functions.unwrap();
+        let names: Vec<_> = functions.iter().map(|decl| decl.name.as_str()).collect();
+        assert_eq!(names, vec!["k8s_image_tag", "k8s_context"]);
+        assert_eq!(functions[1].script.as_deref(), Some("echo team"));
+        assert_eq!(
+            functions[0].process.as_ref().unwrap().method.as_deref(),
+            Some("image_tag")
+        );
+        assert_eq!(plugin.filters.unwrap()[0].name, "k8s_labels");
+    }
+
+    #[test]
+    fn test_shell_params_are_not_interpreted() {
+        let hostile = r#"a"; echo injected; echo "$(echo injected)`echo injected`'"#;
+        let mut args = HashMap::new();
+        args.insert("msg".to_string(), Value::String(hostile.to_string()));
+        let param = Param {
+            name: "msg".to_string(),
+            description: None,
+            ..Default::default()
+        };
+
+        let func_decl = Declaration {
+            name: "positional".to_string(),
+            params: Some(vec![param.clone()]),
+            script: Some("printf '%s' \"$1\"".to_string()),
+            ..Default::default()
+        };
+        let result = func_decl
+            .create_function(&HostEnv::default())
+            .unwrap()
+            .call(&args);
+        assert_eq!(result.unwrap(), Value::String(hostile.to_string()));
+
+        let func_decl = Declaration {
+            name: "exec".to_string(),
+            params: Some(vec![param.clone()]),
+            shell: ShellOptions {
+                exec: Some(vec!["printf".into(), "%s".into(), "<$(msg)>".into()]),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let result = func_decl
+            .create_function(&HostEnv::default())
+            .unwrap()
+            .call(&args);
+        assert_eq!(result.unwrap(), Value::String(format!("<{}>", hostile)));
+
+        let func_decl = Declaration {
+            name: "text".to_string(),
+            params: Some(vec![param]),
+            script: Some("echo $(msg)".to_string()),
+            shell: ShellOptions {
+                param_mode: ParamMode::Text,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        args.insert("msg".to_string(), Value::String("$((1 + 1))".to_string()));
+        let result = func_decl
+            .create_function(&HostEnv::default())
+            .unwrap()
+            .call(&args);
+        assert_eq!(result.unwrap(), Value::String("2\n".to_string()));
+    }
+
+    #[test]
+    fn test_shell_output_formats() {
+        let call = |script: &str, output: OutputFormat| {
+            let func_decl = Declaration {
+                name: "output_test".to_string(),
+                script: Some(script.to_string()),
+                shell: ShellOptions {
+                    output,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            func_decl
+                .create_function(&HostEnv::default())
+                .unwrap()
+                .call(&HashMap::new())
+        };
+
+        assert_eq!(
+            call("echo '{\"a\": [1, 2]}'", OutputFormat::Json).unwrap(),
+            serde_json::json!({"a": [1, 2]})
+        );
+        assert_eq!(
+            call("printf 'a\\nb\\n'", OutputFormat::Lines).unwrap(),
+            serde_json::json!(["a", "b"])
+        );
+        assert_eq!(
+            call("echo ' 42 '", OutputFormat::Number).unwrap(),
+            serde_json::json!(42)
+        );
+        assert_eq!(
+            call("echo 'a: b'", OutputFormat::Yaml).unwrap(),
+            serde_json::json!({"a": "b"})
+        );
+        let err = call("echo nope", OutputFormat::Json).unwrap_err();
+        assert!(err.to_string().contains("'output_test'"), "{}", err);
+    }
+
+    #[test]
+    fn test_shell_filter_stdin_input() {
+        let filter_decl = Declaration {
+            name: "quote".to_string(),
+            script: Some("sed 's/^/> /'".to_string()),
+            shell: ShellOptions {
+                input: InputMode::Stdin,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let filter = filter_decl.create_filter(&HostEnv::default()).unwrap();
+
+        let value = Value::String("it's \"quoted\"\n$(not run)\n".to_string());
+        let result = filter.filter(&value, &HashMap::new()).unwrap();
+        assert_eq!(
+            result,
+            Value::String("> it's \"quoted\"\n> $(not run)\n".to_string())
+        );
+
+        let result = filter
+            .filter(&serde_json::json!({"a": 1}), &HashMap::new())
+            .unwrap();
+        assert_eq!(result, Value::String("> {\"a\":1}".to_string()));
+    }
+
+    #[test]
+    fn test_discover_wasm_module() {
+        let metadata = r#"{\"exports\":[{\"name\":\"upper\",\"kind\":\"filter\",\"params\":[{\"name\":\"n\",\"type\":\"int\"}]},{\"name\":\"now\",\"kind\":\"function\"},{\"name\":\"hidden\",\"kind\":\"function\"}]}"#;
+        let wat = format!(
+            r#"(module
+                 (memory (export "memory") 1)
+                 (data (i32.const 16) "{}")
+                 (func (export "jintemplify_metadata") (result i32)
+                   (i32.store (i32.const 0) (i32.const 16))
+                   (i32.store (i32.const 4) (i32.const {}))
+                   i32.const 0))"#,
+            metadata,
+            metadata.replace("\\", "").len()
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("discover.wat");
+        std::fs::write(&path, wat).unwrap();
+
+        let module: WasmModuleDeclaration = serde_yaml::from_str(&format!(
+            "wasm_module: {}\nprefix: ex_\nexclude: [hidden]",
+            path.display()
+        ))
+        .unwrap();
+        let discovered = module.discover().unwrap();
+        assert_eq!(
+            discovered
+                .iter()
+                .map(|(kind, decl)| (*kind, decl.name.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (ExportKind::Filter, "ex_upper"),
+                (ExportKind::Function, "ex_now")
+            ]
+        );
+        let upper = &discovered[0].1;
+        assert_eq!(upper.wasm.as_ref().unwrap().import, "upper");
+        assert_eq!(upper.params.as_ref().unwrap()[0].name, "n");
+    }
+
+    #[test]
+    fn test_declaration_needs_one_backend() {
+        let host = HostEnv::default();
+        let decl: Declaration = serde_yaml::from_str(
+            "{name: both, template: '{{ 1 }}', script: echo one, exec: [echo, one]}",
+        )
+        .unwrap();
+        let err = decl.create_function(&host).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "'both': only one backend can be set, found template, exec, script"
+        );
+
+        let decl: Declaration = serde_yaml::from_str("{name: none}").unwrap();
+        assert!(decl.create_filter(&host).is_err());
+    }
+
+    #[test]
+    fn test_plugin_tester() {
+        let tester_decl = Declaration {
+            name: "starts_with".to_string(),
+            params: Some(vec![Param {
+                name: "prefix".to_string(),
+                description: None,
+                ..Default::default()
+            }]),
+            script: Some(
+                "case \"$JT_INPUT\" in \"$JT_PARAM_prefix\"*) exit 0;; esac; exit 1".to_string(),
+            ),
+            ..Default::default()
+        };
+        let mut tera = Tera::default();
+        tera.register_tester(
+            "starts_with",
+            tester_decl.create_tester(&HostEnv::default()).unwrap(),
+        );
+
+        let rendered = tera
+            .render_str(
+                r#"{% for v in ["v1.2", "1.2"] %}{% if v is starts_with("v") %}yes{% else %}no{% endif %} {% endfor %}"#,
+                &Context::new(),
+            )
+            .unwrap();
+        assert_eq!(rendered, "yes no ");
+    }
 }
@@ -0,0 +1,405 @@
+// src/process_plugin.rs
+use crate::ast::{Executable, ExportKind, ProcessConfig, ResourceLimits};
+use crate::command::{apply_env_policy, isolate, kill, spawn};
+use crate::host::HostEnv;
+use log::debug;
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tera::Value;
+
+/// Sent as `protocol` with `initialize`.
+const PROTOCOL_VERSION: u64 = 1;
+/// How long a helper may take to answer `shutdown` before it is killed.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
+
+/// A helper process shared by the declarations that run the same command. It
+/// is started on the first call, kept for the rest of the render and
+/// restarted after it died or stopped answering.
+#[derive(Clone)]
+pub struct ProcessHandle {
+    config: Arc<ProcessConfig>,
+    running: Arc<Mutex<Option<Helper>>>,
+}
+
+impl std::fmt::Debug for ProcessHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProcessHandle")
+            .field("command", &self.config.command)
+            .finish_non_exhaustive()
+    }
+}
+
+struct Helper {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    responses: Receiver<String>,
+    next_id: u64,
+    /// What the helper answered to `list`.
+    methods: Vec<String>,
+}
+
+enum RequestError {
+    /// The helper answered with a JSON-RPC error and can take more calls.
+    Remote(String),
+    /// The helper exited, timed out or broke the protocol.
+    Broken(String),
+}
+
+impl RequestError {
+    fn message(self) -> String {
+        match self {
+            RequestError::Remote(message) | RequestError::Broken(message) => message,
+        }
+    }
+}
+
+impl Helper {
+    fn start(
+        config: &ProcessConfig,
+        host: &HostEnv,
+        timeout: Option<Duration>,
+    ) -> Result<Helper, String> {
+        let (program, args) = config
+            .command
+            .split_first()
+            .ok_or("command must not be empty")?;
+        let mut command = Command::new(program);
+        command
+            .args(args)
+            .envs(config.env.iter().flatten())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        command.envs(
+            host.env_from_vars(&config.env_policy)
+                .map_err(|e| e.to_string())?,
+        );
+        if let Some(cwd) = &config.cwd {
+            command.current_dir(cwd);
+        }
+        apply_env_policy(&mut command, &config.env_policy);
+        isolate(&mut command, ResourceLimits::default());
+        let mut child = spawn(&mut command).map_err(|e| format!("failed to start: {}", e))?;
+
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let (sender, responses) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut helper = Helper {
+            stdin: child.stdin.take(),
+            child,
+            responses,
+            next_id: 0,
+            methods: Vec::new(),
+        };
+        helper
+            .request(
+                "initialize",
+                json!({ "protocol": PROTOCOL_VERSION }),
+                timeout,
+            )
+            .map_err(RequestError::message)?;
+        let methods = helper
+            .request("list", Value::Null, timeout)
+            .map_err(RequestError::message)?;
+        helper.methods = serde_json::from_value(methods)
+            .map_err(|e| format!("'list' must return a list of names: {}", e))?;
+        debug!(
+            "process started: {:?} => {:?}",
+            config.command, helper.methods
+        );
+        Ok(helper)
+    }
+
+    fn exited(&mut self) -> String {
+        match self.child.try_wait() {
+            Ok(Some(status)) => format!("exited unexpectedly ({})", status),
+            _ => "closed its output".to_string(),
+        }
+    }
+
+    /// Sends one request and waits for the response with the same id,
+    /// skipping any other message.
+    fn request(
+        &mut self,
+        method: &str,
+        params: Value,
+        timeout: Option<Duration>,
+    ) -> Result<Value, RequestError> {
+        self.next_id += 1;
+        let id = self.next_id;
+        let request = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        let stdin = self.stdin.as_mut().expect("stdin is open while running");
+        if writeln!(stdin, "{}", request)
+            .and_then(|_| stdin.flush())
+            .is_err()
+        {
+            return Err(RequestError::Broken(self.exited()));
+        }
+
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let line = match deadline {
+                Some(deadline) => self
+                    .responses
+                    .recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                None => self
+                    .responses
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            let line = match line {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(RequestError::Broken(format!(
+                        "no response to '{}' within {:.1?}",
+                        method,
+                        timeout.unwrap_or_default()
+                    )))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(RequestError::Broken(self.exited()))
+                }
+            };
+            let response: Value = serde_json::from_str(&line)
+                .map_err(|e| RequestError::Broken(format!("invalid response {:?}: {}", line, e)))?;
+            if response.get("id") != Some(&Value::from(id)) {
+                debug!("ignoring message from process: {}", line);
+                continue;
+            }
+            if let Some(error) = response.get("error") {
+                let message = match error.get("message") {
+                    Some(Value::String(message)) => message.clone(),
+                    _ => error.to_string(),
+                };
+                return Err(RequestError::Remote(message));
+            }
+            return Ok(response.get("result").cloned().unwrap_or(Value::Null));
+        }
+    }
+}
+
+impl Drop for Helper {
+    fn drop(&mut self) {
+        // Ask politely first; a helper that does not answer is killed.
+        if self.stdin.is_some() {
+            let _ = self.request("shutdown", Value::Null, Some(SHUTDOWN_GRACE));
+        }
+        drop(self.stdin.take());
+        kill(&mut self.child);
+    }
+}
+
+impl ProcessHandle {
+    pub fn new(config: &ProcessConfig) -> Self {
+        ProcessHandle {
+            config: Arc::new(ProcessConfig {
+                handle: None,
+                ..config.clone()
+            }),
+            running: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Stops the helper if it is running.
+    pub fn shutdown(&self) {
+        self.running.lock().unwrap().take();
+    }
+
+    /// Calls `method` of the helper, starting it first if needed.
+    fn call(
+        &self,
+        plugin: &str,
+        method: &str,
+        params: Value,
+        host: &HostEnv,
+        timeout: Option<Duration>,
+    ) -> tera::Result<Value> {
+        let error = |message: String| {
+            tera::Error::msg(format!(
+                "Plugin '{}' ({}): {}",
+                plugin,
+                self.config.command.join(" "),
+                message
+            ))
+        };
+        let mut running = self.running.lock().unwrap();
+        if running.is_none() {
+            *running = Some(Helper::start(&self.config, host, timeout).map_err(error)?);
+        }
+        let helper = running.as_mut().expect("helper is running");
+        if !helper.methods.iter().any(|name| name == method) {
+            return Err(error(format!(
+                "the process does not provide '{}' (available: {})",
+                method,
+                helper.methods.join(", ")
+            )));
+        }
+        match helper.request("call", params, timeout) {
+            Ok(result) => Ok(result),
+            Err(RequestError::Remote(message)) => Err(error(message)),
+            Err(RequestError::Broken(message)) => {
+                // Start afresh on the next call.
+                *running = None;
+                Err(error(message))
+            }
+        }
+    }
+}
+
+/// The helpers of a plugin file, shut down when the render is over.
+#[derive(Debug, Default)]
+pub struct Processes(pub Vec<ProcessHandle>);
+
+impl Drop for Processes {
+    fn drop(&mut self) {
+        for handle in self.0.iter() {
+            handle.shutdown();
+        }
+    }
+}
+
+/// A function, filter or tester answered by a helper process.
+pub struct ProcessExecutable {
+    name: String,
+    kind: ExportKind,
+    config: ProcessConfig,
+    host: HostEnv,
+}
+
+impl ProcessExecutable {
+    /// Uses the handle shared through the plugin file, or a helper of its own.
+    pub fn new(name: &str, kind: ExportKind, config: &ProcessConfig, host: &HostEnv) -> Self {
+        let mut config = config.clone();
+        if config.handle.is_none() {
+            config.handle = Some(ProcessHandle::new(&config));
+        }
+        ProcessExecutable {
+            name: name.to_string(),
+            kind,
+            config,
+            host: host.clone(),
+        }
+    }
+}
+
+impl Executable for ProcessExecutable {
+    fn execute(&self, args: &HashMap<String, Value>, value: Option<&Value>) -> tera::Result<Value> {
+        self.execute_in_context(args, value, None)
+    }
+
+    fn execute_in_context(
+        &self,
+        args: &HashMap<String, Value>,
+        value: Option<&Value>,
+        context: Option<&Value>,
+    ) -> tera::Result<Value> {
+        let method = self.config.method.as_deref().unwrap_or(&self.name);
+        let mut params = json!({
+            "name": method,
+            "kind": self.kind,
+            "args": args,
+            "value": value,
+        });
+        if let Some(context) = context {
+            params["context"] = context.clone();
+        }
+        let timeout = self
+            .config
+            .timeout
+            .map(|secs| {
+                Duration::try_from_secs_f64(secs).map_err(|e| {
+                    tera::Error::msg(format!(
+                        "Plugin '{}': invalid timeout {}: {}",
+                        self.name, secs, e
+                    ))
+                })
+            })
+            .transpose()?;
+        self.config
+            .handle
+            .as_ref()
+            .expect("process handle is attached")
+            .call(&self.name, method, params, &self.host, timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The JSON-RPC helper shared with the plugin tests.
+    const HELPER: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/rpc_helper.sh");
+
+    fn helper() -> ProcessConfig {
+        let mut config: ProcessConfig =
+            serde_yaml::from_str(&format!("{{command: [sh, {:?}]}}", HELPER)).unwrap();
+        config.handle = Some(ProcessHandle::new(&config));
+        config
+    }
+
+    #[test]
+    fn test_filter_and_unknown_method() {
+        let config = helper();
+        let host = HostEnv::default();
+        let upper = ProcessExecutable::new("upper", ExportKind::Filter, &config, &host);
+        assert_eq!(
+            upper
+                .execute(&HashMap::new(), Some(&Value::from("abc")))
+                .unwrap(),
+            Value::from("ABC")
+        );
+        let err = ProcessExecutable::new("missing", ExportKind::Function, &config, &host)
+            .execute(&HashMap::new(), None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("does not provide 'missing'"), "{}", err);
+        config.handle.as_ref().unwrap().shutdown();
+    }
+
+    #[test]
+    fn test_restart_after_broken_helper() {
+        let config = helper();
+        let host = HostEnv::default();
+        let call = |name: &str, timeout: Option<f64>| {
+            let config = ProcessConfig {
+                timeout,
+                ..config.clone()
+            };
+            ProcessExecutable::new(name, ExportKind::Function, &config, &host)
+                .execute(&HashMap::new(), None)
+        };
+
+        assert_eq!(call("count", None).unwrap(), Value::from(1));
+        // An error answer leaves the helper running.
+        let err = call("fail", None).unwrap_err().to_string();
+        assert!(err.contains("boom"), "{}", err);
+        assert_eq!(call("count", None).unwrap(), Value::from(2));
+
+        let err = call("crash", None).unwrap_err().to_string();
+        assert!(
+            err.contains("exited unexpectedly") || err.contains("closed its output"),
+            "{}",
+            err
+        );
+        assert_eq!(call("count", None).unwrap(), Value::from(1));
+
+        let err = call("slow", Some(0.2)).unwrap_err().to_string();
+        assert!(err.contains("no response to 'call'"), "{}", err);
+        assert_eq!(call("count", None).unwrap(), Value::from(1));
+        config.handle.as_ref().unwrap().shutdown();
+    }
+}
@@ -0,0 +1,210 @@
+// src/rhai_plugin.rs
+use crate::ast::{Executable, Param, RhaiOptions};
+use log::{debug, info};
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Dynamic, Engine, Scope, AST};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tera::Value;
+
+const DEFAULT_MAX_OPERATIONS: u64 = 10_000_000;
+
+/// A function, filter or tester written in Rhai. The script sees each
+/// parameter as a variable of the same name, the filtered or tested value as
+/// `input` and the render context as `context`; its last expression is the
+/// result.
+pub struct RhaiExecutable {
+    name: String,
+    ast: AST,
+    params: Vec<String>,
+    max_operations: u64,
+    timeout: Option<Duration>,
+}
+
+/// An engine with the standard packages only: `import` cannot load files,
+/// `print` and `debug` go to the log, and each call is bounded.
+fn engine(name: &str, max_operations: u64, timeout: Option<Duration>) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_module_resolver(DummyModuleResolver::new())
+        .set_max_operations(max_operations)
+        .set_max_call_levels(64)
+        .set_max_string_size(16 * 1024 * 1024)
+        .set_max_array_size(1024 * 1024)
+        .set_max_map_size(1024 * 1024);
+    let plugin = name.to_string();
+    engine.on_print(move |text| info!("{}: {}", plugin, text));
+    let plugin = name.to_string();
+    engine.on_debug(move |text, _, pos| debug!("{} @ {}: {}", plugin, pos, text));
+    if let Some(timeout) = timeout {
+        let started = Instant::now();
+        engine.on_progress(move |_| {
+            (started.elapsed() > timeout)
+                .then(|| Dynamic::from(format!("timed out after {:.1?}", timeout)))
+        });
+    }
+    engine
+}
+
+impl RhaiExecutable {
+    /// Compiles the declaration's `rhai` or `rhai_file`, if it has one.
+    pub fn new(
+        name: &str,
+        options: &RhaiOptions,
+        source: Option<String>,
+        params: &Option<Vec<Param>>,
+        timeout: Option<f64>,
+    ) -> anyhow::Result<Option<Self>> {
+        let Some(source) = source else {
+            return Ok(None);
+        };
+        let timeout = timeout
+            .map(Duration::try_from_secs_f64)
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("'{}': invalid timeout: {}", name, e))?;
+        let max_operations = options.max_operations.unwrap_or(DEFAULT_MAX_OPERATIONS);
+        let ast = engine(name, max_operations, timeout)
+            .compile(&source)
+            .map_err(|e| anyhow::anyhow!("'{}': invalid rhai script: {}", name, e))?;
+        Ok(Some(RhaiExecutable {
+            name: name.to_string(),
+            ast,
+            params: params
+                .iter()
+                .flatten()
+                .map(|param| param.name.clone())
+                .collect(),
+            max_operations,
+            timeout,
+        }))
+    }
+}
+
+/// The script of a declaration: `rhai`, or the content of `rhai_file`.
+pub fn rhai_source(name: &str, options: &RhaiOptions) -> anyhow::Result<Option<String>> {
+    match (&options.rhai, &options.rhai_file) {
+        (Some(_), Some(_)) => anyhow::bail!("'{}': rhai and rhai_file cannot both be set", name),
+        (Some(source), None) => Ok(Some(source.clone())),
+        (None, Some(path)) => std::fs::read_to_string(path)
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("'{}': Failed to read rhai file {}: {}", name, path, e)),
+        (None, None) => Ok(None),
+    }
+}
+
+impl Executable for RhaiExecutable {
+    fn execute(&self, args: &HashMap<String, Value>, value: Option<&Value>) -> tera::Result<Value> {
+        self.execute_in_context(args, value, None)
+    }
+
+    fn execute_in_context(
+        &self,
+        args: &HashMap<String, Value>,
+        value: Option<&Value>,
+        context: Option<&Value>,
+    ) -> tera::Result<Value> {
+        let error = |e: Box<rhai::EvalAltResult>| match *e {
+            // Rhai only reports that the script was stopped, not why.
+            rhai::EvalAltResult::ErrorTerminated(reason, _) => {
+                tera::Error::msg(format!("Plugin '{}': {}", self.name, reason))
+            }
+            e => tera::Error::msg(format!("Plugin '{}': {}", self.name, e)),
+        };
+        let mut scope = Scope::new();
+        for name in self.params.iter().filter(|name| *name != "input") {
+            let arg = match args.get(name) {
+                Some(arg) => rhai::serde::to_dynamic(arg).map_err(error)?,
+                None => Dynamic::UNIT,
+            };
+            scope.push_dynamic(name.clone(), arg);
+        }
+        if let Some(value) = value {
+            scope.push_dynamic("input", rhai::serde::to_dynamic(value).map_err(error)?);
+        }
+        if let Some(context) = context {
+            scope.push_dynamic("context", rhai::serde::to_dynamic(context).map_err(error)?);
+        }
+        let result = engine(&self.name, self.max_operations, self.timeout)
+            .eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast)
+            .map_err(error)?;
+        rhai::serde::from_dynamic(&result).map_err(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(source: &str, max_operations: Option<u64>, timeout: Option<f64>) -> RhaiExecutable {
+        let options = RhaiOptions {
+            max_operations,
+            ..Default::default()
+        };
+        let params = serde_yaml::from_str("[{name: word}]").unwrap();
+        RhaiExecutable::new(
+            "script",
+            &options,
+            Some(source.to_string()),
+            &params,
+            timeout,
+        )
+        .unwrap()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_rhai_scope() {
+        let args = [("word".to_string(), Value::from("ab"))].into();
+        let result = script(
+            "#{ word: word, input: input, vars: context.vars }",
+            None,
+            None,
+        )
+        .execute_in_context(
+            &args,
+            Some(&Value::from(3)),
+            Some(&serde_json::json!({"vars": {"a": 1}})),
+        )
+        .unwrap();
+        assert_eq!(
+            result,
+            serde_json::json!({"word": "ab", "input": 3, "vars": {"a": 1}})
+        );
+    }
+
+    #[test]
+    fn test_rhai_limits() {
+        let err = script("loop {}", Some(1000), None)
+            .execute(&HashMap::new(), None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Too many operations"), "{}", err);
+
+        // Without an operation limit, the timeout stops the script.
+        let started = Instant::now();
+        let err = script("loop {}", Some(0), Some(0.2))
+            .execute(&HashMap::new(), None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("timed out after"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_rhai_sandbox() {
+        let err = script(r#"import "/etc/passwd" as p; 1"#, None, None)
+            .execute(&HashMap::new(), None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("/etc/passwd"), "{}", err);
+
+        let invalid = RhaiExecutable::new(
+            "invalid",
+            &RhaiOptions::default(),
+            Some("let = ;".to_string()),
+            &None,
+            None,
+        );
+        assert!(invalid.is_err());
+    }
+}
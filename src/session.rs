@@ -0,0 +1,324 @@
+// src/session.rs
+use crate::ast::{ResourceLimits, SessionConfig, SessionIsolation};
+use crate::command::{
+    apply_env_policy, isolate, kill, log_stderr, spawn, CommandOutput, RunError, RunOptions,
+};
+use log::debug;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use tempfile::TempDir;
+
+/// Reads call ids from stdin and runs `<dir>/<id>.sh` with its stdin and
+/// outputs redirected to the `.in`, `.out` and `.err` files next to it,
+/// answering `<id> <status>` on stdout. `$1` is the directory, `$2` is 1 when
+/// each call runs in a subshell.
+const DRIVER: &str = r#"
+__jt_dir=$1
+__jt_isolate=$2
+if [ -f "$__jt_dir/setup.sh" ]; then
+  . "$__jt_dir/setup.sh" </dev/null >&2 || exit
+fi
+echo ready
+while IFS= read -r __jt_call; do
+  if [ "$__jt_isolate" = 1 ]; then
+    ( . "$__jt_dir/$__jt_call.sh" ) <"$__jt_dir/$__jt_call.in" >"$__jt_dir/$__jt_call.out" 2>"$__jt_dir/$__jt_call.err"
+  else
+    . "$__jt_dir/$__jt_call.sh" <"$__jt_dir/$__jt_call.in" >"$__jt_dir/$__jt_call.out" 2>"$__jt_dir/$__jt_call.err"
+  fi
+  echo "$__jt_call $?"
+done
+"#;
+
+/// Quotes `value` for a POSIX shell.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// A long-lived interpreter shared by the `session: true` declarations of a
+/// plugin file. It is started on the first call and restarted after a call
+/// failed to complete.
+#[derive(Clone)]
+pub struct Session {
+    config: Arc<SessionConfig>,
+    running: Arc<Mutex<Option<Running>>>,
+}
+
+impl std::fmt::Debug for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Session")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+struct Running {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    responses: Receiver<String>,
+    dir: TempDir,
+    calls: u64,
+}
+
+fn session_error(message: impl std::fmt::Display) -> tera::Error {
+    tera::Error::msg(format!("Plugin session: {}", message))
+}
+
+impl Running {
+    fn start(config: &SessionConfig) -> tera::Result<Running> {
+        let dir = tempfile::Builder::new()
+            .prefix("jintemplify-session-")
+            .tempdir()
+            .map_err(session_error)?;
+        if let Some(setup) = &config.setup {
+            fs::write(dir.path().join("setup.sh"), setup).map_err(session_error)?;
+        }
+        let log = fs::File::create(dir.path().join("session.err")).map_err(session_error)?;
+
+        let mut parts = config.interpreter.split_whitespace();
+        let program = parts
+            .next()
+            .ok_or_else(|| session_error("interpreter must not be empty"))?;
+        let mut command = Command::new(program);
+        command
+            .args(parts)
+            .arg("-c")
+            .arg(DRIVER)
+            .arg("jintemplify-session")
+            .arg(dir.path())
+            .arg(match config.isolation {
+                SessionIsolation::Subshell => "1",
+                SessionIsolation::None => "0",
+            })
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(log);
+        if let Some(cwd) = &config.cwd {
+            command.current_dir(cwd);
+        }
+        apply_env_policy(&mut command, &config.env_policy);
+        isolate(&mut command, ResourceLimits::default());
+        let mut child = spawn(&mut command).map_err(|e| {
+            session_error(format!("failed to start '{}': {}", config.interpreter, e))
+        })?;
+
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let (sender, responses) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut running = Running {
+            stdin: child.stdin.take(),
+            child,
+            responses,
+            dir,
+            calls: 0,
+        };
+        match running.responses.recv() {
+            Ok(line) if line == "ready" => {
+                debug!("session started: {}", config.interpreter);
+                Ok(running)
+            }
+            _ => Err(running.died("setup failed")),
+        }
+    }
+
+    /// The error for a session that exited, with what it wrote to stderr.
+    fn died(&mut self, what: &str) -> tera::Error {
+        let _ = self.child.wait();
+        let log = fs::read_to_string(self.dir.path().join("session.err")).unwrap_or_default();
+        session_error(format!("{}: {}", what, log.trim_end()))
+    }
+
+    fn call(
+        &mut self,
+        script: &str,
+        display: &str,
+        options: &RunOptions,
+    ) -> tera::Result<CommandOutput> {
+        self.calls += 1;
+        let id = self.calls.to_string();
+        let dir = self.dir.path().to_path_buf();
+        let path = |ext: &str| dir.join(format!("{}.{}", id, ext));
+        fs::write(path("sh"), script).map_err(session_error)?;
+        fs::write(path("in"), options.stdin.as_deref().unwrap_or_default())
+            .map_err(session_error)?;
+
+        let started = Instant::now();
+        let sent = self
+            .stdin
+            .as_mut()
+            .expect("stdin is open while running")
+            .write_all(format!("{}\n", id).as_bytes());
+        if sent.is_err() {
+            return Err(self.died("exited"));
+        }
+        let response = match options.timeout {
+            Some(timeout) => self.responses.recv_timeout(timeout),
+            None => self
+                .responses
+                .recv()
+                .map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let status = match response {
+            Ok(line) => match line.split_once(' ') {
+                Some((call, status)) if call == id => status.parse::<i32>().ok(),
+                _ => return Err(session_error(format!("unexpected response '{}'", line))),
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(RunError::TimedOut(started.elapsed()).into_tera(display, options))
+            }
+            Err(RecvTimeoutError::Disconnected) => return Err(self.died("exited")),
+        };
+
+        let read = |ext: &str| -> tera::Result<String> {
+            let path = path(ext);
+            if let Some(max) = options.max_output {
+                let len = fs::metadata(&path).map_err(session_error)?.len();
+                if len > max as u64 {
+                    return Err(RunError::OutputTooLarge(max).into_tera(display, options));
+                }
+            }
+            let content = fs::read(&path).map_err(session_error)?;
+            Ok(String::from_utf8_lossy(&content).into_owned())
+        };
+        let output = CommandOutput {
+            stdout: read("out")?,
+            stderr: read("err")?,
+            status,
+        };
+        for ext in ["sh", "in", "out", "err"] {
+            let _ = fs::remove_file(path(ext));
+        }
+        debug!(
+            "session call: {} => status: {:?}, output_str: {}",
+            display, output.status, output.stdout
+        );
+        log_stderr(&output, options);
+        Ok(output)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        // Closing stdin ends the driver loop; anything still running is killed.
+        drop(self.stdin.take());
+        kill(&mut self.child);
+    }
+}
+
+impl Session {
+    pub fn new(config: SessionConfig) -> Self {
+        Session {
+            config: Arc::new(config),
+            running: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Runs `script` in the session. `options` supply stdin, the timeout and
+    /// the output limit; resource limits do not apply to sessions.
+    pub fn run(
+        &self,
+        script: &str,
+        display: &str,
+        options: &RunOptions,
+    ) -> tera::Result<CommandOutput> {
+        let mut running = self.running.lock().unwrap();
+        if running.is_none() {
+            *running = Some(Running::start(&self.config)?);
+        }
+        let result = running
+            .as_mut()
+            .expect("session is running")
+            .call(script, display, options);
+        if result.is_err() {
+            // The interpreter may be stuck or gone; start afresh next time.
+            *running = None;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn session(yaml: &str) -> Session {
+        Session::new(serde_yaml::from_str(yaml).unwrap())
+    }
+
+    fn run(session: &Session, script: &str, options: &RunOptions) -> tera::Result<CommandOutput> {
+        session.run(script, script, options)
+    }
+
+    #[test]
+    fn test_session_calls() {
+        let shared = session("{isolation: none}");
+        let options = RunOptions::default();
+        run(&shared, "COUNT=1; cd /", &options).unwrap();
+        let output = run(&shared, "echo \"$COUNT $PWD\"", &options).unwrap();
+        assert_eq!(output.stdout, "1 /\n");
+        // By default every call runs in its own subshell of one interpreter.
+        let isolated = session("{}");
+        let pid = run(&isolated, "LEAKED=1; echo $$", &options)
+            .unwrap()
+            .stdout;
+        let output = run(&isolated, "echo \"${LEAKED:-none} $$\"", &options).unwrap();
+        assert_eq!(output.stdout, format!("none {}", pid));
+
+        let options = RunOptions {
+            stdin: Some(b"from stdin".to_vec()),
+            ..Default::default()
+        };
+        let output = run(&shared, "cat; echo oops >&2; return 3", &options).unwrap();
+        assert_eq!(output.stdout, "from stdin");
+        assert_eq!(output.stderr, "oops\n");
+        assert_eq!(output.status, Some(3));
+
+        let options = RunOptions {
+            max_output: Some(4),
+            ..Default::default()
+        };
+        let err = run(&shared, "echo too long", &options).unwrap_err();
+        assert!(err.to_string().contains("more than 4 bytes"), "{}", err);
+    }
+
+    #[test]
+    fn test_session_restarts() {
+        let broken = session("{setup: 'echo no such helper >&2; exit 1'}");
+        let err = run(&broken, "true", &RunOptions::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Plugin session: setup failed: no such helper"
+        );
+
+        let shared = session("{isolation: none}");
+        let options = RunOptions {
+            timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        run(&shared, "STATE=kept", &options).unwrap();
+        let err = run(&shared, "sleep 5", &options).unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+        // The next call gets a fresh interpreter.
+        let output = run(&shared, "echo \"${STATE:-fresh}\"", &options).unwrap();
+        assert_eq!(output.stdout, "fresh\n");
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("$(rm -rf /)"), "'$(rm -rf /)'");
+    }
+}
@@ -1,12 +1,36 @@
-use crate::ast::{Executable, Param};
-use crate::command::{execute_shell_command, replace_placeholder, tera_to_str};
+use crate::ast::{Executable, InputMode, Param, ParamMode, ResultMode, ShellOptions};
+use crate::command::{
+    exec_command, parse_output, replace_placeholder, run_captured, script_command, tera_to_str,
+    CommandOutput, RunOptions,
+};
+use crate::host::HostEnv;
+use crate::session::shell_quote;
 use log::debug;
 use std::collections::HashMap;
+use std::io::Write;
+use std::process::Command;
+use tempfile::TempPath;
+
+/// Size up to which the render context is also passed as `JT_CONTEXT`. Larger
+/// contexts, e.g. with big `vars`, are only written to `JT_CONTEXT_FILE`, so
+/// that they cannot exceed the limits on the size of the environment.
+pub const MAX_ENV_CONTEXT: usize = 4096;
 
 pub struct ShellCommand {
+    pub name: String,
     pub script: String,
     pub params: Option<Vec<Param>>,
     pub env: Option<HashMap<String, String>>,
+    pub options: ShellOptions,
+    /// Resolves `env_from_vars`.
+    pub host: HostEnv,
+}
+
+/// A child process ready to run, with the temporary file holding its script.
+struct Prepared {
+    command: Command,
+    display: String,
+    _script: Option<TempPath>,
 }
 
 pub struct ShellFunction {
@@ -17,6 +41,35 @@ pub struct ShellFilter {
     pub command: ShellCommand,
 }
 
+/// The render context of a call, in the file named by `JT_CONTEXT_FILE`,
+/// which is removed once the call is over, and as `JT_CONTEXT` when it is at
+/// most `MAX_ENV_CONTEXT` bytes.
+struct CallContext {
+    json: String,
+    file: TempPath,
+}
+
+impl CallContext {
+    fn new(context: &tera::Value) -> tera::Result<Self> {
+        let json = serde_json::to_string(context)?;
+        let file = tempfile::Builder::new()
+            .prefix("jintemplify-context-")
+            .suffix(".json")
+            .tempfile()
+            .and_then(|mut file| {
+                file.write_all(json.as_bytes())?;
+                Ok(file.into_temp_path())
+            })
+            .map_err(|e| tera::Error::msg(format!("Failed to write context file: {}", e)))?;
+        Ok(CallContext { json, file })
+    }
+}
+
+/// Passes when the script exits with status 0, or one of `ok_codes`.
+pub struct ShellTester {
+    pub command: ShellCommand,
+}
+
 fn prepare_command(
     script: &str,
     params: &Option<Vec<Param>>,
@@ -31,22 +84,6 @@ fn prepare_command(
     Ok(cmd)
 }
 
-impl Executable for ShellFunction {
-    fn execute(
-        &self,
-        args: &HashMap<String, tera::Value>,
-        _value: Option<&tera::Value>,
-    ) -> tera::Result<tera::Value> {
-        let cmd = prepare_command(&self.command.script, &self.command.params, args)?;
-        debug!("==> ShellFunction: {}", cmd);
-        Ok(tera::Value::String(execute_shell_command(
-            &cmd,
-            &self.command.env,
-            None,
-        )?))
-    }
-}
-
 pub fn prepare_command_filter(
     script: &str,
     params: &Option<Vec<Param>>,
@@ -68,23 +105,294 @@ pub fn prepare_command_filter(
     Ok(cmd)
 }
 
+impl ShellCommand {
+    /// Resolves each declared parameter to the string handed to the script.
+    /// `args` are already validated, so only optional parameters can be
+    /// missing; they are passed as empty strings.
+    fn param_values(
+        &self,
+        args: &HashMap<String, tera::Value>,
+    ) -> tera::Result<Vec<(String, String)>> {
+        let mut values = Vec::new();
+        for param in self.params.iter().flatten() {
+            let value = match args.get(&param.name).or(param.default.as_ref()) {
+                Some(value) => tera_to_str(value)?,
+                None => String::new(),
+            };
+            values.push((param.name.clone(), value));
+        }
+        Ok(values)
+    }
+
+    /// Whether `status` counts as success under `ok_codes`.
+    fn succeeded(&self, status: Option<i32>) -> bool {
+        match (status, &self.options.ok_codes) {
+            (Some(code), Some(ok_codes)) => ok_codes.contains(&code),
+            (Some(code), None) => code == 0,
+            (None, _) => false,
+        }
+    }
+
+    /// Turns a finished command into the value of the call, according to
+    /// `allow_failure`, `output` and `result`.
+    fn result(&self, output: CommandOutput, display: &str) -> tera::Result<tera::Value> {
+        if !self.succeeded(output.status) && !self.options.allow_failure {
+            return Err(output.failure(display));
+        }
+        let stdout = parse_output(&self.name, self.options.output, output.stdout)?;
+        Ok(match self.options.result {
+            ResultMode::Output => stdout,
+            ResultMode::Full => serde_json::json!({
+                "stdout": stdout,
+                "stderr": output.stderr,
+                "status": output.status,
+            }),
+        })
+    }
+
+    fn run_options(&self, value: Option<&tera::Value>) -> tera::Result<RunOptions> {
+        let stdin = match (self.options.input, value) {
+            (InputMode::Stdin, Some(value)) => Some(tera_to_str(value)?.into_bytes()),
+            _ => None,
+        };
+        Ok(RunOptions {
+            stdin,
+            ..RunOptions::from_shell_options(&self.name, &self.options)?
+        })
+    }
+
+    /// The filtered value when it is passed as `JT_INPUT`.
+    fn env_input(&self, value: Option<&tera::Value>) -> tera::Result<Option<String>> {
+        match self.options.input {
+            InputMode::Env => value.map(tera_to_str).transpose(),
+            InputMode::Stdin => Ok(None),
+        }
+    }
+
+    /// The declared `env` and `env_from_vars` plus the parameters, input and
+    /// context variables.
+    fn environment(
+        &self,
+        values: &[(String, String)],
+        input: &Option<String>,
+        context: Option<&CallContext>,
+    ) -> tera::Result<HashMap<String, String>> {
+        let mut env = self.env.clone().unwrap_or_default();
+        let from_vars = self
+            .host
+            .env_from_vars(&self.options.env_policy)
+            .map_err(|e| tera::Error::msg(format!("Plugin '{}': {}", self.name, e)))?;
+        env.extend(from_vars);
+        for (name, value) in values.iter() {
+            env.insert(format!("JT_PARAM_{}", name), value.clone());
+        }
+        if let Some(input) = input {
+            env.insert("JT_INPUT".to_string(), input.clone());
+        }
+        if let Some(context) = context {
+            if context.json.len() <= MAX_ENV_CONTEXT {
+                env.insert("JT_CONTEXT".to_string(), context.json.clone());
+            }
+            env.insert(
+                "JT_CONTEXT_FILE".to_string(),
+                context.file.to_string_lossy().into_owned(),
+            );
+        }
+        Ok(env)
+    }
+
+    /// Builds the child process. Unless `param_mode: text` is set, values are
+    /// never parsed by a shell: they are passed as `JT_PARAM_<name>` variables
+    /// and positional arguments, and the filtered value as `JT_INPUT` (or on
+    /// stdin with `input: stdin`).
+    fn command(
+        &self,
+        args: &HashMap<String, tera::Value>,
+        value: Option<&tera::Value>,
+        context: Option<&CallContext>,
+    ) -> tera::Result<Prepared> {
+        let values = self.param_values(args)?;
+        let input = self.env_input(value)?;
+        let env = self.environment(&values, &input, context)?;
+
+        let interpreter = self.options.interpreter.as_deref();
+        let built = if let Some(argv) = &self.options.exec {
+            let argv = argv
+                .iter()
+                .map(|arg| {
+                    let mut arg = arg.clone();
+                    for (name, value) in values.iter() {
+                        arg = arg.replace(&format!("$({})", name), value);
+                    }
+                    if let Some(input) = &input {
+                        arg = arg.replace("$(input)", input);
+                    }
+                    arg
+                })
+                .collect::<Vec<_>>();
+            let display = argv.join(" ");
+            exec_command(&argv, Some(&env)).map(|command| Prepared {
+                command,
+                display,
+                _script: None,
+            })
+        } else if self.options.param_mode == ParamMode::Text {
+            let cmd = match value {
+                Some(value) => prepare_command_filter(&self.script, &self.params, value, args)?,
+                None => prepare_command(&self.script, &self.params, args)?,
+            };
+            script_command(&cmd, Some(&env), interpreter, &[]).map(|(command, script)| Prepared {
+                command,
+                display: cmd,
+                _script: Some(script),
+            })
+        } else {
+            let positional = values
+                .into_iter()
+                .map(|(_, value)| value)
+                .collect::<Vec<_>>();
+            script_command(&self.script, Some(&env), interpreter, &positional).map(
+                |(command, script)| Prepared {
+                    command,
+                    display: self.script.clone(),
+                    _script: Some(script),
+                },
+            )
+        };
+        let mut prepared = built.map_err(|e| {
+            tera::Error::msg(format!(
+                "Failed to prepare command for plugin '{}': {}",
+                self.name, e
+            ))
+        })?;
+        if let Some(cwd) = &self.options.cwd {
+            prepared.command.current_dir(cwd);
+        }
+        Ok(prepared)
+    }
+
+    /// Builds the script run inside a session: the same variables and
+    /// positional arguments as `command`, set up by quoted assignments.
+    fn session_script(
+        &self,
+        args: &HashMap<String, tera::Value>,
+        value: Option<&tera::Value>,
+        context: Option<&CallContext>,
+    ) -> tera::Result<String> {
+        let values = self.param_values(args)?;
+        let input = self.env_input(value)?;
+        let mut env = self
+            .environment(&values, &input, context)?
+            .into_iter()
+            .collect::<Vec<_>>();
+        env.sort();
+
+        let mut script = String::new();
+        for (name, value) in env {
+            script.push_str(&format!("export {}={}\n", name, shell_quote(&value)));
+        }
+        if let Some(cwd) = &self.options.cwd {
+            script.push_str(&format!("cd {} || exit 1\n", shell_quote(cwd)));
+        }
+        if self.options.param_mode == ParamMode::Text {
+            script.push_str(&match value {
+                Some(value) => prepare_command_filter(&self.script, &self.params, value, args)?,
+                None => prepare_command(&self.script, &self.params, args)?,
+            });
+        } else {
+            script.push_str("set --");
+            for (_, value) in values.iter() {
+                script.push(' ');
+                script.push_str(&shell_quote(value));
+            }
+            script.push('\n');
+            script.push_str(&self.script);
+        }
+        script.push('\n');
+        Ok(script)
+    }
+
+    /// Runs the script, in the plugin's session when it has one, and returns
+    /// its output along with the text naming it in messages.
+    fn run(
+        &self,
+        args: &HashMap<String, tera::Value>,
+        value: Option<&tera::Value>,
+        context: Option<&tera::Value>,
+    ) -> tera::Result<(CommandOutput, String)> {
+        let options = self.run_options(value)?;
+        let context = context.map(CallContext::new).transpose()?;
+        if let Some(session) = &self.options.session_handle {
+            let script = self.session_script(args, value, context.as_ref())?;
+            debug!("==> {} (session): {}", self.name, self.script);
+            let output = session.run(&script, &self.script, &options)?;
+            return Ok((output, self.script.clone()));
+        }
+        let prepared = self.command(args, value, context.as_ref())?;
+        debug!("==> {}: {}", self.name, prepared.display);
+        let output = run_captured(prepared.command, &prepared.display, &options)?;
+        Ok((output, prepared.display))
+    }
+}
+
+impl Executable for ShellFunction {
+    fn execute(
+        &self,
+        args: &HashMap<String, tera::Value>,
+        value: Option<&tera::Value>,
+    ) -> tera::Result<tera::Value> {
+        self.execute_in_context(args, value, None)
+    }
+
+    fn execute_in_context(
+        &self,
+        args: &HashMap<String, tera::Value>,
+        _value: Option<&tera::Value>,
+        context: Option<&tera::Value>,
+    ) -> tera::Result<tera::Value> {
+        let (output, display) = self.command.run(args, None, context)?;
+        self.command.result(output, &display)
+    }
+}
+
 impl Executable for ShellFilter {
     fn execute(
         &self,
         args: &HashMap<String, tera::Value>,
         value: Option<&tera::Value>,
     ) -> tera::Result<tera::Value> {
-        let cmd = prepare_command_filter(
-            &self.command.script,
-            &self.command.params,
-            value.unwrap(),
-            args,
-        )?;
-        debug!("===> ShellFilter: {}", cmd);
-        Ok(tera::Value::String(execute_shell_command(
-            &cmd,
-            &self.command.env,
-            None,
-        )?))
+        self.execute_in_context(args, value, None)
+    }
+
+    fn execute_in_context(
+        &self,
+        args: &HashMap<String, tera::Value>,
+        value: Option<&tera::Value>,
+        context: Option<&tera::Value>,
+    ) -> tera::Result<tera::Value> {
+        let value = value.unwrap_or(&tera::Value::Null);
+        let (output, display) = self.command.run(args, Some(value), context)?;
+        self.command.result(output, &display)
+    }
+}
+
+impl Executable for ShellTester {
+    fn execute(
+        &self,
+        args: &HashMap<String, tera::Value>,
+        value: Option<&tera::Value>,
+    ) -> tera::Result<tera::Value> {
+        self.execute_in_context(args, value, None)
+    }
+
+    fn execute_in_context(
+        &self,
+        args: &HashMap<String, tera::Value>,
+        value: Option<&tera::Value>,
+        context: Option<&tera::Value>,
+    ) -> tera::Result<tera::Value> {
+        let value = value.unwrap_or(&tera::Value::Null);
+        let (output, _) = self.command.run(args, Some(value), context)?;
+        Ok(tera::Value::Bool(self.command.succeeded(output.status)))
     }
 }
@@ -0,0 +1,270 @@
+// src/signature.rs
+use crate::ast::{Param, ParamType};
+use crate::command::tera_to_str;
+use regex::Regex;
+use std::collections::HashMap;
+use tera::Value;
+
+/// The declared parameters of a plugin, checked against every call before
+/// it reaches the wasm or shell backend.
+#[derive(Debug, Default)]
+pub struct Signature {
+    plugin: String,
+    params: Vec<(Param, Option<Regex>)>,
+    /// Without a `params` list, arguments are passed through unchecked.
+    declared: bool,
+    /// For filters, a parameter named `input` stands for the filtered value.
+    filter: bool,
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "bool",
+        Value::Number(n) if n.is_f64() => "float",
+        Value::Number(_) => "int",
+        Value::String(_) => "string",
+        Value::Array(_) => "list",
+        Value::Object(_) => "object",
+    }
+}
+
+fn has_type(value: &Value, kind: ParamType) -> bool {
+    match kind {
+        ParamType::String => value.is_string(),
+        ParamType::Int => value.is_i64() || value.is_u64(),
+        ParamType::Float => value.is_number(),
+        ParamType::Bool => value.is_boolean(),
+        ParamType::List => value.is_array(),
+        ParamType::Object => value.is_object(),
+    }
+}
+
+impl Signature {
+    /// Compiles the `pattern`s of `params` and checks their defaults.
+    pub fn new(plugin: &str, params: &Option<Vec<Param>>, filter: bool) -> anyhow::Result<Self> {
+        let mut signature = Signature {
+            plugin: plugin.to_string(),
+            params: Vec::new(),
+            declared: params.is_some(),
+            filter,
+        };
+        for param in params.iter().flatten() {
+            let pattern = param
+                .pattern
+                .as_deref()
+                .map(Regex::new)
+                .transpose()
+                .map_err(|e| {
+                    anyhow::anyhow!(
+                        "Plugin '{}': invalid pattern for parameter '{}': {}",
+                        plugin,
+                        param.name,
+                        e
+                    )
+                })?;
+            signature.params.push((param.clone(), pattern));
+        }
+        if signature.params.iter().filter(|(p, _)| p.variadic).count() > 1 {
+            anyhow::bail!("Plugin '{}': only one parameter can be variadic", plugin);
+        }
+        for (param, pattern) in signature.params.iter() {
+            if let Some(default) = &param.default {
+                signature
+                    .check(param, pattern, default)
+                    .map_err(|e| anyhow::anyhow!("{} (default value)", e))?;
+            }
+        }
+        Ok(signature)
+    }
+
+    /// Names of the parameters that take an argument of their own, in order.
+    pub fn positional(&self) -> Vec<String> {
+        self.params
+            .iter()
+            .filter(|(param, _)| !param.variadic)
+            .map(|(param, _)| param.name.clone())
+            .collect()
+    }
+
+    fn error(&self, param: &str, message: impl std::fmt::Display) -> tera::Error {
+        tera::Error::msg(format!(
+            "Plugin '{}': parameter '{}' {}",
+            self.plugin, param, message
+        ))
+    }
+
+    fn check(&self, param: &Param, pattern: &Option<Regex>, value: &Value) -> tera::Result<()> {
+        if let Some(kind) = param.kind {
+            if !has_type(value, kind) {
+                return Err(self.error(
+                    &param.name,
+                    format!(
+                        "must be {}, got {} {}",
+                        format!("{:?}", kind).to_lowercase(),
+                        type_name(value),
+                        value
+                    ),
+                ));
+            }
+        }
+        if let Some(choices) = &param.choices {
+            if !choices.contains(value) {
+                let choices = choices
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                return Err(self.error(
+                    &param.name,
+                    format!("must be one of {}, got {}", choices, value),
+                ));
+            }
+        }
+        if let Some(pattern) = pattern {
+            if !pattern.is_match(&tera_to_str(value)?) {
+                return Err(self.error(
+                    &param.name,
+                    format!("must match '{}', got {}", pattern, value),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks `args` and returns them with defaults filled in and undeclared
+    /// arguments moved to the variadic parameter.
+    pub fn validate(&self, args: &HashMap<String, Value>) -> tera::Result<HashMap<String, Value>> {
+        if !self.declared {
+            return Ok(args.clone());
+        }
+        let declared = |name: &str| self.params.iter().any(|(p, _)| p.name == name);
+        let variadic = self.params.iter().find(|(p, _)| p.variadic);
+        let mut extra = tera::Map::new();
+        for (name, value) in args.iter() {
+            let collects = variadic.is_some_and(|(p, _)| &p.name == name);
+            if declared(name) && !collects {
+                continue;
+            }
+            if variadic.is_none() {
+                let expected = self.positional().join(", ");
+                return Err(tera::Error::msg(format!(
+                    "Plugin '{}': unknown argument '{}' (expected: {})",
+                    self.plugin, name, expected
+                )));
+            }
+            extra.insert(name.clone(), value.clone());
+        }
+
+        let mut validated = HashMap::new();
+        for (param, pattern) in self.params.iter() {
+            if param.variadic {
+                validated.insert(
+                    param.name.clone(),
+                    Value::Object(std::mem::take(&mut extra)),
+                );
+                continue;
+            }
+            let value = match (args.get(&param.name), &param.default) {
+                (Some(value), _) => value.clone(),
+                (None, Some(default)) => default.clone(),
+                (None, None) if self.filter && param.name == "input" => continue,
+                (None, None) if param.required.unwrap_or(true) => {
+                    return Err(self.error(&param.name, "is required"))
+                }
+                (None, None) => continue,
+            };
+            self.check(param, pattern, &value)?;
+            validated.insert(param.name.clone(), value);
+        }
+        Ok(validated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signature() -> Signature {
+        let params: Vec<Param> = serde_yaml::from_str(
+            r#"
+- name: count
+  type: int
+  default: 3
+- name: mode
+  enum: [fast, slow]
+- name: tag
+  pattern: "^v[0-9]+$"
+  required: false
+- name: rest
+  variadic: true
+"#,
+        )
+        .unwrap();
+        Signature::new("f", &Some(params), false).unwrap()
+    }
+
+    fn args(json: &str) -> HashMap<String, Value> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn error(json: &str) -> String {
+        signature().validate(&args(json)).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_validate_args() {
+        let validated = signature()
+            .validate(&args(r#"{"mode": "fast", "x": 1}"#))
+            .unwrap();
+        assert_eq!(
+            validated,
+            args(r#"{"count": 3, "mode": "fast", "rest": {"x": 1}}"#)
+        );
+
+        assert_eq!(error(r#"{}"#), "Plugin 'f': parameter 'mode' is required");
+        assert_eq!(
+            error(r#"{"mode": "fast", "count": "3"}"#),
+            "Plugin 'f': parameter 'count' must be int, got string \"3\""
+        );
+        assert_eq!(
+            error(r#"{"mode": "medium"}"#),
+            "Plugin 'f': parameter 'mode' must be one of \"fast\", \"slow\", got \"medium\""
+        );
+        assert_eq!(
+            error(r#"{"mode": "fast", "tag": "1.0"}"#),
+            "Plugin 'f': parameter 'tag' must match '^v[0-9]+$', got \"1.0\""
+        );
+
+        let strict = Signature::new(
+            "g",
+            &Some(vec![Param {
+                name: "a".to_string(),
+                ..Default::default()
+            }]),
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            strict
+                .validate(&args(r#"{"a": 1, "b": 2}"#))
+                .unwrap_err()
+                .to_string(),
+            "Plugin 'g': unknown argument 'b' (expected: a)"
+        );
+
+        let undeclared = Signature::new("p", &None, false).unwrap();
+        assert_eq!(
+            undeclared.validate(&args(r#"{"a": 1, "b": 2}"#)).unwrap(),
+            args(r#"{"a": 1, "b": 2}"#)
+        );
+
+        let bad_default = Param {
+            name: "n".to_string(),
+            kind: Some(ParamType::Int),
+            default: Some(Value::from("x")),
+            ..Default::default()
+        };
+        assert!(Signature::new("h", &Some(vec![bad_default]), false).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+// src/template_plugin.rs
+use crate::ast::{Executable, ExportKind, OutputFormat};
+use crate::command::parse_output;
+use crate::host::{HostEnv, RenderRequest};
+use std::collections::HashMap;
+use tera::Value;
+
+/// A function, filter or tester whose body is a Tera template, rendered with
+/// its arguments, `input`, `vars` and `context` in scope. Everything
+/// registered on the main template environment can be used in the body.
+pub struct TemplateExecutable {
+    name: String,
+    kind: ExportKind,
+    template: String,
+    output: OutputFormat,
+    host: HostEnv,
+}
+
+impl TemplateExecutable {
+    /// Checks the syntax of `template` up front, so that a broken body fails
+    /// when the plugin file is loaded.
+    pub fn new(
+        name: &str,
+        kind: ExportKind,
+        template: &str,
+        output: OutputFormat,
+        host: &HostEnv,
+    ) -> anyhow::Result<Self> {
+        tera::Tera::default()
+            .add_raw_template(name, template)
+            .map_err(|e| anyhow::Error::new(e).context(format!("'{}': invalid template", name)))?;
+        Ok(TemplateExecutable {
+            name: name.to_string(),
+            kind,
+            template: template.to_string(),
+            output,
+            host: host.clone(),
+        })
+    }
+}
+
+impl Executable for TemplateExecutable {
+    fn execute(&self, args: &HashMap<String, Value>, value: Option<&Value>) -> tera::Result<Value> {
+        self.execute_in_context(args, value, None)
+    }
+
+    fn execute_in_context(
+        &self,
+        args: &HashMap<String, Value>,
+        value: Option<&Value>,
+        render_context: Option<&Value>,
+    ) -> tera::Result<Value> {
+        let mut context: tera::Map<String, Value> = args.clone().into_iter().collect();
+        if let Some(value) = value {
+            context.insert("input".to_string(), value.clone());
+        }
+        if let Some(render_context) = render_context {
+            context.insert("context".to_string(), render_context.clone());
+        }
+        let rendered = self
+            .host
+            .render(&RenderRequest {
+                template: self.template.clone(),
+                context,
+            })
+            .map_err(|e| tera::Error::chain(format!("Plugin '{}'", self.name), e))?;
+        // A tester's body renders to `true` or `false`.
+        let output = match (self.kind, self.output) {
+            (ExportKind::Tester, OutputFormat::Raw) => OutputFormat::Bool,
+            (_, output) => output,
+        };
+        parse_output(&self.name, output, rendered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn executable(kind: ExportKind, template: &str, output: OutputFormat) -> TemplateExecutable {
+        let host = HostEnv::default();
+        host.set_tera(&tera::Tera::default());
+        TemplateExecutable::new("body", kind, template, output, &host).unwrap()
+    }
+
+    #[test]
+    fn test_template_scope_and_output() {
+        let filter = executable(
+            ExportKind::Filter,
+            r#"{"input": {{ input }}, "n": {{ n }}, "name": "{{ context.template_name }}"}"#,
+            OutputFormat::Json,
+        );
+        let args = [("n".to_string(), Value::from(2))].into();
+        assert_eq!(
+            filter
+                .execute_in_context(
+                    &args,
+                    Some(&Value::from(1)),
+                    Some(&serde_json::json!({"template_name": "main.j2"})),
+                )
+                .unwrap(),
+            serde_json::json!({"input": 1, "n": 2, "name": "main.j2"})
+        );
+
+        let err = executable(ExportKind::Function, "{{ missing }}", OutputFormat::Raw)
+            .execute(&HashMap::new(), None)
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Plugin 'body'"), "{}", err);
+    }
+
+    #[test]
+    fn test_template_tester_output() {
+        let tester = executable(ExportKind::Tester, "{{ input > 2 }}", OutputFormat::Raw);
+        assert_eq!(
+            tester
+                .execute(&HashMap::new(), Some(&Value::from(3)))
+                .unwrap(),
+            Value::Bool(true)
+        );
+        let err = executable(ExportKind::Tester, "maybe", OutputFormat::Raw)
+            .execute(&HashMap::new(), Some(&Value::from(3)))
+            .unwrap_err();
+        assert!(err.to_string().contains("not valid bool"), "{}", err);
+    }
+
+    #[test]
+    fn test_invalid_template() {
+        let err = TemplateExecutable::new(
+            "broken",
+            ExportKind::Function,
+            "{{ unclosed",
+            OutputFormat::Raw,
+            &HostEnv::default(),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.to_string(), "'broken': invalid template");
+    }
+}
@@ -1,30 +1,76 @@
-use crate::ast::{Executable, WasmDeclartion, WasmFilter, WasmFunction};
+use crate::ast::{
+    Executable, HostPermission, ModuleMetadata, WasmDeclartion, WasmFilter, WasmFunction,
+    WasmTester,
+};
+use crate::host::{CallRequest, HostEnv, RenderRequest};
+use anyhow::Context as _Context;
 use jintemplify_plugin;
 use log::{debug, info};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::slice;
 use std::str;
 use tera;
 use wasmtime::*;
 
 jintemplify_plugin::host_plugin!();
 
+/// Export through which a guest announces the host ABI it was built against.
+const ABI_VERSION_EXPORT: &str = "jintemplify_abi_version";
+/// Oldest ABI still accepted: input is appended to the end of linear memory.
+const MIN_ABI_VERSION: u32 = 1;
+/// Current ABI: input buffers come from the guest's `guest_alloc`/`guest_dealloc`.
+const ABI_VERSION: u32 = 2;
+/// Export describing every function and filter the module provides.
+pub const METADATA_EXPORT: &str = "jintemplify_metadata";
+const WASM_PAGE_SIZE: u64 = 65536;
+const RETURN_VALUES_SIZE: u32 = 8;
+
 impl Executable for WasmFunction {
     fn execute(
+        &self,
+        args: &HashMap<String, tera::Value>,
+        value: Option<&tera::Value>,
+    ) -> tera::Result<tera::Value> {
+        self.execute_in_context(args, value, None)
+    }
+
+    fn execute_in_context(
         &self,
         args: &HashMap<String, tera::Value>,
         _value: Option<&tera::Value>,
+        context: Option<&tera::Value>,
     ) -> tera::Result<tera::Value> {
-        let mut execute =
-            WasmExecutor::new(&self.decl).map_err(|e| tera::Error::msg(e.to_string()))?;
-        Ok(execute
-            .execute(args, None)
-            .map_err(|e| tera::Error::msg(e.to_string()))?)
-        //Ok(execute_wasm(&self.decl, args, None).map_err(|e| tera::Error::msg(e.to_string()))?)
+        self.decl.run(args, None, context)
     }
 }
 
 impl WasmDeclartion {
+    fn run(
+        &self,
+        args: &HashMap<String, tera::Value>,
+        value: Option<&tera::Value>,
+        context: Option<&tera::Value>,
+    ) -> tera::Result<tera::Value> {
+        WasmExecutor::new(self)
+            .and_then(|mut executor| executor.execute(args, value, context))
+            .map_err(|e| self.to_tera_error(e))
+    }
+
+    /// Names the plugin and export in the error, with the wasm backtrace for traps.
+    fn to_tera_error(&self, err: anyhow::Error) -> tera::Error {
+        let mut message = format!(
+            "wasm plugin '{}' ({}, export '{}') failed: ",
+            self.name, self.wasm.path, self.wasm.import
+        );
+        match err.downcast_ref::<WasmBacktrace>() {
+            Some(backtrace) => {
+                message.push_str(&err.root_cause().to_string());
+                message.push_str(&format!("\nwasm backtrace:\n{}", backtrace));
+            }
+            None => message.push_str(&format!("{:#}", err)),
+        }
+        tera::Error::msg(message)
+    }
     fn filter_params(
         &self,
         user_params: &HashMap<String, serde_json::Value>,
@@ -34,8 +80,8 @@ impl WasmDeclartion {
         // Iterate through each parameter defined in the function_config
         if let Some(params) = &self.params {
             for param in params {
-                // Check if the parameter exists in the provided map
-                if let Some(value) = user_params.get(&param.name) {
+                // Check if the parameter exists in the provided map, or has a default
+                if let Some(value) = user_params.get(&param.name).or(param.default.as_ref()) {
                     filtered_map.insert(param.name.clone(), value.clone());
                 }
             }
@@ -47,8 +93,9 @@ impl WasmDeclartion {
 
 pub struct WasmExecutor<'a> {
     func_decl: &'a WasmDeclartion,
-    store: Store<()>,
+    store: Store<GuestState>,
     instance: Instance,
+    abi_version: u32,
 }
 
 fn get_module(engine: &Engine, file: &str) -> Result<Module, anyhow::Error> {
@@ -56,141 +103,378 @@ fn get_module(engine: &Engine, file: &str) -> Result<Module, anyhow::Error> {
     Ok(module)
 }
 
-fn get_imports<T>(store: &mut Store<T>) -> Vec<Extern> {
-    let print_type = wasmtime::FuncType::new(
-        [wasmtime::ValType::I32, wasmtime::ValType::I32]
-            .iter()
-            .cloned(),
-        [].iter().cloned(),
-    );
-    let print_func = wasmtime::Func::new(
+/// Per-instance data reachable from host imports.
+pub struct GuestState {
+    host: HostEnv,
+    permissions: Vec<HostPermission>,
+}
+
+/// Host side of the guest's output envelope; a guest reports failure by
+/// setting `error` instead of (or alongside) `result`.
+#[derive(Debug, Deserialize)]
+struct OutputWrapper {
+    #[serde(default)]
+    result: serde_json::Value,
+    error: Option<PluginError>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PluginError {
+    message: String,
+    code: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct HostResponse {
+    result: serde_json::Value,
+    error: Option<PluginError>,
+}
+
+fn print_import(store: &mut Store<GuestState>) -> Func {
+    Func::wrap(
         store,
-        print_type,
-        |mut caller: Caller<'_, T>, params: &[wasmtime::Val], _results: &mut [wasmtime::Val]| {
-            let mem = match caller.get_export("memory") {
-                Some(Extern::Memory(mem)) => Ok(mem),
-                _ => Err(anyhow::anyhow!("failed to find host memory")),
-            }
-            .unwrap();
-            let data = mem
+        |mut caller: Caller<'_, GuestState>, ptr: u32, len: u32| -> anyhow::Result<()> {
+            let start = ptr as usize;
+            let memory = caller_memory(&mut caller)?;
+            let data = memory
                 .data(&caller)
-                .get(params[0].unwrap_i32() as usize..)
-                .and_then(|arr| arr.get(..params[1].unwrap_i32() as usize));
-
-            // Read the string from the WebAssembly memory
-            let string = match data {
-                Some(data) => match str::from_utf8(data) {
-                    Ok(s) => Ok(s),
-                    Err(_) => Err(anyhow::anyhow!("invalid utf-8")),
-                },
-                None => Err(anyhow::anyhow!("invalid utf-8")),
-            }?;
-
-            info!("{}", string);
+                .get(start..start.saturating_add(len as usize))
+                .ok_or_else(|| anyhow::anyhow!("print: message out of bounds"))?;
+            info!("{}", String::from_utf8_lossy(data));
             Ok(())
         },
-    );
-    vec![wasmtime::Extern::Func(print_func.into())]
+    )
+}
+
+fn caller_memory(caller: &mut Caller<'_, GuestState>) -> anyhow::Result<Memory> {
+    match caller.get_export("memory") {
+        Some(Extern::Memory(mem)) => Ok(mem),
+        _ => Err(anyhow::anyhow!("failed to find guest memory")),
+    }
+}
+
+/// Copies `bytes` into a buffer from the guest's `guest_alloc` and returns
+/// `(ptr << 32) | len`; the guest releases it with `guest_dealloc`.
+fn write_to_guest(caller: &mut Caller<'_, GuestState>, bytes: &[u8]) -> anyhow::Result<u64> {
+    let guest_alloc = match caller.get_export("guest_alloc") {
+        Some(Extern::Func(func)) => func.typed::<u32, u32>(&caller)?,
+        _ => {
+            return Err(anyhow::anyhow!(
+                "host callbacks require the 'guest_alloc' export"
+            ))
+        }
+    };
+    let len = u32::try_from(bytes.len())?;
+    let ptr = guest_alloc.call(&mut *caller, len)?;
+    caller_memory(caller)?
+        .write(&mut *caller, ptr as usize, bytes)
+        .map_err(|_| anyhow::anyhow!("guest_alloc returned an out of bounds buffer"))?;
+    Ok(((ptr as u64) << 32) | len as u64)
+}
+
+/// Builds a `(ptr, len) -> packed (ptr, len)` import taking a JSON request and
+/// answering with a JSON `HostResponse`, refused unless `permission` was granted.
+fn host_import<F>(store: &mut Store<GuestState>, permission: HostPermission, handler: F) -> Func
+where
+    F: Fn(&HostEnv, &[u8]) -> tera::Result<serde_json::Value> + Send + Sync + 'static,
+{
+    Func::wrap(
+        store,
+        move |mut caller: Caller<'_, GuestState>, ptr: u32, len: u32| -> anyhow::Result<u64> {
+            let start = ptr as usize;
+            let request = caller_memory(&mut caller)?
+                .data(&caller)
+                .get(start..start.saturating_add(len as usize))
+                .map(|bytes| bytes.to_vec())
+                .ok_or_else(|| anyhow::anyhow!("host call request out of bounds"))?;
+
+            let state = caller.data();
+            let response = if !state.permissions.contains(&permission) {
+                HostResponse {
+                    result: serde_json::Value::Null,
+                    error: Some(PluginError {
+                        message: format!("plugin is not granted the '{:?}' permission", permission),
+                        code: None,
+                    }),
+                }
+            } else {
+                match handler(&state.host, &request) {
+                    Ok(result) => HostResponse {
+                        result,
+                        error: None,
+                    },
+                    Err(e) => HostResponse {
+                        result: serde_json::Value::Null,
+                        error: Some(PluginError {
+                            message: e.to_string(),
+                            code: None,
+                        }),
+                    },
+                }
+            };
+            debug!("host response: {:?}", response);
+            write_to_guest(&mut caller, &serde_json::to_vec(&response)?)
+        },
+    )
+}
+
+/// Module from which guests import host functions.
+const HOST_MODULE: &str = "env";
+
+/// Resolves the module's imports by module and name, so a guest only needs to
+/// import the host functions it actually uses.
+fn get_imports(store: &mut Store<GuestState>, module: &Module) -> anyhow::Result<Vec<Extern>> {
+    let mut imports = Vec::new();
+    for import in module.imports() {
+        let func = match (import.module(), import.name()) {
+            (HOST_MODULE, "print") => print_import(store),
+            (HOST_MODULE, "host_get_var") => {
+                host_import(store, HostPermission::Vars, |host, request| {
+                    let request: serde_json::Value = serde_json::from_slice(request)?;
+                    match request.get("name").and_then(|name| name.as_str()) {
+                        Some(name) => Ok(host.get_var(name)),
+                        None => Err(tera::Error::msg("host_get_var: 'name' is required")),
+                    }
+                })
+            }
+            (HOST_MODULE, "host_call_function") => {
+                host_import(store, HostPermission::Functions, |host, request| {
+                    host.call(&serde_json::from_slice::<CallRequest>(request)?)
+                })
+            }
+            (HOST_MODULE, "host_render") => {
+                host_import(store, HostPermission::Render, |host, request| {
+                    let request = serde_json::from_slice::<RenderRequest>(request)?;
+                    Ok(serde_json::Value::String(host.render(&request)?))
+                })
+            }
+            (module, name) => return Err(anyhow::anyhow!("unknown import '{}.{}'", module, name)),
+        };
+        imports.push(Extern::Func(func));
+    }
+    Ok(imports)
 }
 
 impl<'a> WasmExecutor<'a> {
     pub fn new(func_decl: &'a WasmDeclartion) -> Result<Self> {
         let engine = Engine::default();
         let module = get_module(&engine, &func_decl.wasm.path)?;
-        let mut store = Store::new(&engine, ());
-        let imports = get_imports(&mut store);
+        let mut store = Store::new(
+            &engine,
+            GuestState {
+                host: func_decl.host.clone(),
+                permissions: func_decl.wasm.permissions.clone().unwrap_or_default(),
+            },
+        );
+        let imports = get_imports(&mut store, &module)?;
         let instance = Instance::new(&mut store, &module, &imports)?;
-        Ok(Self {
+        let mut executor = Self {
             func_decl,
             store: store,
             instance,
-        })
+            abi_version: MIN_ABI_VERSION,
+        };
+        executor.abi_version = executor.negotiate_abi()?;
+        Ok(executor)
+    }
+
+    /// Rejects modules whose ABI version this host does not speak, before any
+    /// plugin code runs. Modules without the version export are treated as ABI 1.
+    fn negotiate_abi(&mut self) -> anyhow::Result<u32> {
+        let version = match self
+            .instance
+            .get_typed_func::<(), u32>(&mut self.store, ABI_VERSION_EXPORT)
+        {
+            Ok(func) => func.call(&mut self.store, ())?,
+            Err(_) => MIN_ABI_VERSION,
+        };
+        if !(MIN_ABI_VERSION..=ABI_VERSION).contains(&version) {
+            return Err(anyhow::anyhow!(
+                "{}: unsupported plugin ABI version {} (supported: {}..={})",
+                self.func_decl.wasm.path,
+                version,
+                MIN_ABI_VERSION,
+                ABI_VERSION
+            ));
+        }
+        if version >= 2 {
+            for export in ["guest_alloc", "guest_dealloc"] {
+                if self.instance.get_func(&mut self.store, export).is_none() {
+                    return Err(anyhow::anyhow!(
+                        "{}: ABI version {} requires the '{}' export",
+                        self.func_decl.wasm.path,
+                        version,
+                        export
+                    ));
+                }
+            }
+        }
+        debug!("{}: abi version={}", self.func_decl.wasm.path, version);
+        Ok(version)
     }
 
-    fn free_return_values(&mut self, ptr: u32) {
+    fn free_return_values(&mut self, ptr: u32) -> anyhow::Result<()> {
         let guest_free = self
             .instance
-            .get_typed_func::<u32, ()>(&mut self.store, "guest_free")
-            .unwrap();
+            .get_typed_func::<u32, ()>(&mut self.store, "guest_free")?;
+
+        guest_free.call(&mut self.store, ptr)
+    }
+
+    /// Copies `input` into a buffer owned by the guest and returns its offset.
+    fn write_input(&mut self, memory: &Memory, input: &[u8]) -> anyhow::Result<u32> {
+        let len = u32::try_from(input.len())?;
+        let ptr = if self.abi_version >= 2 {
+            let guest_alloc = self
+                .instance
+                .get_typed_func::<u32, u32>(&mut self.store, "guest_alloc")?;
+            guest_alloc.call(&mut self.store, len)?
+        } else {
+            // ABI 1 guests have no allocator: append whole pages to the end of memory.
+            let ptr = u32::try_from(memory.data_size(&self.store))?;
+            let pages = (input.len() as u64).div_ceil(WASM_PAGE_SIZE);
+            memory.grow(&mut self.store, pages)?;
+            ptr
+        };
+        memory
+            .write(&mut self.store, ptr as usize, input)
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "guest returned an out of bounds input buffer: ptr={}, len={}",
+                    ptr,
+                    len
+                )
+            })?;
+        Ok(ptr)
+    }
 
-        guest_free.call(&mut self.store, ptr as u32).unwrap();
+    fn release_input(&mut self, ptr: u32, len: u32) -> anyhow::Result<()> {
+        if self.abi_version >= 2 {
+            let guest_dealloc = self
+                .instance
+                .get_typed_func::<(u32, u32), ()>(&mut self.store, "guest_dealloc")?;
+            guest_dealloc.call(&mut self.store, (ptr, len))?;
+        }
+        Ok(())
     }
 
+    fn read_guest_bytes(&self, memory: &Memory, ptr: u32, len: u32) -> anyhow::Result<Vec<u8>> {
+        let start = ptr as usize;
+        start
+            .checked_add(len as usize)
+            .and_then(|end| memory.data(&self.store).get(start..end))
+            .map(|bytes| bytes.to_vec())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "guest memory access out of bounds: ptr={}, len={}, memory size={}",
+                    ptr,
+                    len,
+                    memory.data_size(&self.store)
+                )
+            })
+    }
+
+    /// Reads the bytes described by the ReturnValues at `ptr`, which is two
+    /// little-endian u32s in guest memory: (ptr, len).
+    fn read_return_values(&self, memory: &Memory, ptr: u32) -> anyhow::Result<Vec<u8>> {
+        let return_values = self.read_guest_bytes(memory, ptr, RETURN_VALUES_SIZE)?;
+        let result_ptr = u32::from_le_bytes(return_values[0..4].try_into()?);
+        let result_len = u32::from_le_bytes(return_values[4..8].try_into()?);
+        debug!("return_values={}, len={}", result_ptr, result_len);
+        self.read_guest_bytes(memory, result_ptr, result_len)
+    }
+
+    /// Calls the module's metadata export. The document is static guest data,
+    /// so unlike call results it is not handed back to `guest_free`.
+    pub fn metadata(&mut self) -> anyhow::Result<ModuleMetadata> {
+        let memory = self
+            .instance
+            .get_memory(&mut self.store, "memory")
+            .ok_or_else(|| anyhow::anyhow!("{}: no 'memory' export", self.func_decl.wasm.path))?;
+        let function = self
+            .instance
+            .get_typed_func::<(), u32>(&mut self.store, METADATA_EXPORT)
+            .with_context(|| {
+                format!(
+                    "{}: no '{}' export to discover plugins from",
+                    self.func_decl.wasm.path, METADATA_EXPORT
+                )
+            })?;
+        let ptr = function.call(&mut self.store, ())?;
+        let bytes = self.read_return_values(&memory, ptr)?;
+        serde_json::from_slice(&bytes)
+            .with_context(|| format!("{}: malformed module metadata", self.func_decl.wasm.path))
+    }
+
+    /// The input document; the render context, when the declaration asked
+    /// for it, is added as `context` next to `params`.
     fn prepare_input_data(
         &self,
         arg: &HashMap<String, tera::Value>,
         value: Option<&tera::Value>,
+        context: Option<&tera::Value>,
     ) -> serde_json::Value {
         let params = match &value {
             Some(v) => vec![serde_json::json!(v), serde_json::json!(arg)],
             None => vec![serde_json::json!(arg)],
         };
-        serde_json::json!(jintemplify_plugin::InputWrapper { params: params })
+        let mut input = serde_json::json!(jintemplify_plugin::InputWrapper { params: params });
+        if let (Some(context), serde_json::Value::Object(input)) = (context, &mut input) {
+            input.insert("context".to_string(), context.clone());
+        }
+        input
     }
 
     pub fn execute(
         &mut self,
         arg: &HashMap<String, tera::Value>,
         value: Option<&tera::Value>,
+        context: Option<&tera::Value>,
     ) -> anyhow::Result<tera::Value> {
         let arg = self.func_decl.filter_params(arg);
-        let input_data = self.prepare_input_data(&arg, value);
+        let input_data = self.prepare_input_data(&arg, value, context);
         let input_bytes = input_data.to_string().into_bytes();
-        let memory = self.instance.get_memory(&mut self.store, "memory").unwrap();
-
-        // Allocate space in wasm memory for the JSON input
-        let input_ptr = memory.data_mut(&mut self.store).len() as i32;
-        memory.grow(&mut self.store, (input_bytes.len() as u32).into())?;
-        memory.data_mut(&mut self.store)
-            [input_ptr as usize..input_ptr as usize + input_bytes.len()]
-            .copy_from_slice(&input_bytes);
-
-        let function = self
+        let memory = self
             .instance
-            .get_typed_func::<(u32, u32), u32>(&mut self.store, &self.func_decl.wasm.import)?;
-
-        let ptr = function.call(
-            &mut self.store,
-            (input_ptr as u32, input_bytes.len() as u32),
-        )?;
-
-        let result_ptr = ptr as usize;
-        let result_len = std::mem::size_of::<jintemplify_plugin::ReturnValues>();
-
-        let memory_slice = unsafe {
-            std::slice::from_raw_parts_mut(
-                memory.data_mut(&mut self.store)[result_ptr..].as_mut_ptr(),
-                result_len,
-            )
-        };
+            .get_memory(&mut self.store, "memory")
+            .ok_or_else(|| anyhow::anyhow!("{}: no 'memory' export", self.func_decl.wasm.path))?;
 
-        let return_values: &mut jintemplify_plugin::ReturnValues =
-            unsafe { &mut *(memory_slice.as_mut_ptr() as *mut jintemplify_plugin::ReturnValues) };
+        let input_ptr = self.write_input(&memory, &input_bytes)?;
+        let input_len = input_bytes.len() as u32;
 
-        debug!(
-            "return_values={}, len={}",
-            (*return_values).ptr,
-            (*return_values).len
-        );
-        let result_ptr = (*return_values).ptr as usize;
-        let result_len = (*return_values).len as usize;
-
-        // Extract the result string
-        let result_str = unsafe {
-            let result_bytes =
-                slice::from_raw_parts(memory.data(&self.store)[result_ptr..].as_ptr(), result_len);
-            std::str::from_utf8(result_bytes)?
-        };
+        let called = self
+            .instance
+            .get_typed_func::<(u32, u32), u32>(&mut self.store, &self.func_decl.wasm.import)
+            .and_then(|function| function.call(&mut self.store, (input_ptr, input_len)));
+        // The input buffer is handed back even when the call failed, and the
+        // call's error takes precedence over a failure to release it.
+        let released = self.release_input(input_ptr, input_len);
+        let ptr = called?;
+        released?;
 
-        self.free_return_values(ptr);
+        let result_bytes = self.read_return_values(&memory, ptr);
+        let freed = self.free_return_values(ptr);
+        let result_bytes = result_bytes?;
+        freed?;
+        let result_str = str::from_utf8(&result_bytes)?;
 
-        let output: jintemplify_plugin::OutputWrapper = match serde_json::from_str(result_str) {
+        let output: OutputWrapper = match serde_json::from_str(result_str) {
             Ok(val) => val,
-            Err(err) => return Err(anyhow::anyhow!(err)),
+            Err(err) => return Err(anyhow::anyhow!("malformed plugin output: {}", err)),
         };
 
-        debug!("jintemplify_plugin::OutputWrapper :{:?}", output);
-        Ok(output.result)
+        debug!("OutputWrapper :{:?}", output);
+        match output.error {
+            Some(PluginError {
+                message,
+                code: Some(code),
+            }) => Err(anyhow::anyhow!("{} (code {})", message, code)),
+            Some(PluginError {
+                message,
+                code: None,
+            }) => Err(anyhow::anyhow!(message)),
+            None => Ok(output.result),
+        }
     }
 }
 
@@ -200,11 +484,240 @@ impl Executable for WasmFilter {
         args: &HashMap<String, tera::Value>,
         value: Option<&tera::Value>,
     ) -> tera::Result<tera::Value> {
-        //Ok(execute_wasm(&self.decl, args, value).map_err(|e| tera::Error::msg(e.to_string()))?)
-        let mut execute =
-            WasmExecutor::new(&self.decl).map_err(|e| tera::Error::msg(e.to_string()))?;
-        Ok(execute
-            .execute(args, value)
-            .map_err(|e| tera::Error::msg(e.to_string()))?)
+        self.execute_in_context(args, value, None)
+    }
+
+    fn execute_in_context(
+        &self,
+        args: &HashMap<String, tera::Value>,
+        value: Option<&tera::Value>,
+        context: Option<&tera::Value>,
+    ) -> tera::Result<tera::Value> {
+        self.decl.run(args, value, context)
+    }
+}
+
+impl Executable for WasmTester {
+    fn execute(
+        &self,
+        args: &HashMap<String, tera::Value>,
+        value: Option<&tera::Value>,
+    ) -> tera::Result<tera::Value> {
+        self.execute_in_context(args, value, None)
+    }
+
+    fn execute_in_context(
+        &self,
+        args: &HashMap<String, tera::Value>,
+        value: Option<&tera::Value>,
+        context: Option<&tera::Value>,
+    ) -> tera::Result<tera::Value> {
+        match self.decl.run(args, value, context)? {
+            tera::Value::Bool(b) => Ok(tera::Value::Bool(b)),
+            other => Err(tera::Error::msg(format!(
+                "wasm tester '{}' must return a JSON bool, got {}",
+                self.decl.name, other
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Wasm;
+
+    const GUEST_WAT: &str = r#"
+        (module
+          (import "env" "print" (func (param i32 i32)))
+          (memory (export "memory") 1)
+          (global $next (mut i32) (i32.const 1024))
+          (data (i32.const 16) "{\"result\":\"ok\"}")
+          (func (export "jintemplify_abi_version") (result i32) i32.const 2)
+          (func (export "guest_alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            global.get $next
+            local.set $ptr
+            global.get $next
+            local.get $len
+            i32.add
+            global.set $next
+            local.get $ptr)
+          (global $released (export "released") (mut i32) (i32.const 0))
+          (func (export "guest_dealloc") (param i32 i32)
+            global.get $released
+            i32.const 1
+            i32.add
+            global.set $released)
+          (func (export "guest_free") (param i32))
+          (func (export "ok") (param i32 i32) (result i32)
+            (i32.store (i32.const 0) (i32.const 16))
+            (i32.store (i32.const 4) (i32.const 15))
+            i32.const 0)
+          (data (i32.const 128) "{\"error\":{\"message\":\"bad input\",\"code\":3}}")
+          (func (export "fails") (param i32 i32) (result i32)
+            (i32.store (i32.const 0) (i32.const 128))
+            (i32.store (i32.const 4) (i32.const 42))
+            i32.const 0)
+          (func (export "traps") (param i32 i32) (result i32)
+            unreachable)
+          (func (export "out_of_bounds") (param i32 i32) (result i32)
+            (i32.store (i32.const 0) (i32.const 65530))
+            (i32.store (i32.const 4) (i32.const 100))
+            i32.const 0))
+    "#;
+
+    /// Writes `wat` to a fresh directory, which lives as long as the returned
+    /// guard.
+    fn write_module(wat: &str) -> (tempfile::TempDir, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("module.wat");
+        std::fs::write(&path, wat).unwrap();
+        (dir, path.to_string_lossy().into_owned())
+    }
+
+    fn declaration(path: &str, import: &str) -> WasmDeclartion {
+        WasmDeclartion {
+            wasm: Wasm {
+                path: path.to_string(),
+                import: import.to_string(),
+                permissions: None,
+            },
+            params: None,
+            name: "test_plugin".to_string(),
+            host: HostEnv::default(),
+        }
+    }
+
+    #[test]
+    fn test_guest_alloc_protocol() {
+        let (_dir, path) = write_module(GUEST_WAT);
+        let decl = declaration(&path, "ok");
+        let mut executor = WasmExecutor::new(&decl).unwrap();
+        assert_eq!(executor.abi_version, 2);
+
+        let result = executor.execute(&HashMap::new(), None, None).unwrap();
+        assert_eq!(result, tera::Value::String("ok".to_string()));
+        let memory = executor
+            .instance
+            .get_memory(&mut executor.store, "memory")
+            .unwrap();
+        assert_eq!(memory.size(&executor.store), 1);
+    }
+
+    #[test]
+    fn test_input_is_released_when_the_call_fails() {
+        let (_dir, path) = write_module(GUEST_WAT);
+        for import in ["traps", "missing_export"] {
+            let decl = declaration(&path, import);
+            let mut executor = WasmExecutor::new(&decl).unwrap();
+            assert!(executor.execute(&HashMap::new(), None, None).is_err());
+            let released = executor
+                .instance
+                .get_global(&mut executor.store, "released")
+                .unwrap()
+                .get(&mut executor.store);
+            assert_eq!(released.i32(), Some(1), "{}", import);
+        }
+    }
+
+    #[test]
+    fn test_imports_from_other_modules_are_rejected() {
+        let (_dir, path) = write_module(
+            r#"(module
+                 (import "wasi_snapshot_preview1" "print" (func (param i32 i32)))
+                 (memory (export "memory") 1))"#,
+        );
+        let decl = declaration(&path, "ok");
+        let err = WasmExecutor::new(&decl).err().unwrap();
+        assert!(
+            err.to_string()
+                .contains("unknown import 'wasi_snapshot_preview1.print'"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_out_of_bounds_return_is_an_error() {
+        let (_dir, path) = write_module(GUEST_WAT);
+        let decl = declaration(&path, "out_of_bounds");
+        let mut executor = WasmExecutor::new(&decl).unwrap();
+        let err = executor.execute(&HashMap::new(), None, None).unwrap_err();
+        assert!(err.to_string().contains("out of bounds"), "{}", err);
+    }
+
+    #[test]
+    fn test_unsupported_abi_is_rejected() {
+        let wat = GUEST_WAT.replace("i32.const 2)", "i32.const 99)");
+        let (_dir, path) = write_module(&wat);
+        let decl = declaration(&path, "ok");
+        let err = WasmExecutor::new(&decl).err().unwrap();
+        assert!(
+            err.to_string().contains("unsupported plugin ABI"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_host_get_var_requires_permission() {
+        let wat = GUEST_WAT.replace(
+            "(memory (export \"memory\") 1)",
+            r#"(import "env" "host_get_var" (func $get_var (param i32 i32) (result i64)))
+                  (memory (export "memory") 1)
+                  (data (i32.const 64) "{\"name\":\"greeting\"}")
+                  (func (export "get_var") (param i32 i32) (result i32)
+                    (local $packed i64)
+                    (local.set $packed (call $get_var (i32.const 64) (i32.const 19)))
+                    (i32.store (i32.const 0)
+                      (i32.wrap_i64 (i64.shr_u (local.get $packed) (i64.const 32))))
+                    (i32.store (i32.const 4) (i32.wrap_i64 (local.get $packed)))
+                    i32.const 0)"#,
+        );
+        let (_dir, path) = write_module(&wat);
+        let mut decl = declaration(&path, "get_var");
+        decl.host
+            .set_vars(&serde_json::json!({"greeting": "hi"}))
+            .unwrap();
+
+        let err = decl
+            .run(&HashMap::new(), None, None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("not granted the 'Vars' permission"), "{}", err);
+
+        decl.wasm.permissions = Some(vec![HostPermission::Vars]);
+        let result = WasmExecutor::new(&decl)
+            .unwrap()
+            .execute(&HashMap::new(), None, None)
+            .unwrap();
+        assert_eq!(result, tera::Value::String("hi".to_string()));
+    }
+
+    #[test]
+    fn test_guest_errors_become_tera_errors() {
+        let (_dir, path) = write_module(GUEST_WAT);
+
+        let err = declaration(&path, "fails")
+            .run(&HashMap::new(), None, None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("'test_plugin'"), "{}", err);
+        assert!(err.contains("bad input (code 3)"), "{}", err);
+
+        let err = declaration(&path, "traps")
+            .run(&HashMap::new(), None, None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("export 'traps'"), "{}", err);
+        assert!(err.contains("unreachable"), "{}", err);
+        assert!(err.contains("wasm backtrace"), "{}", err);
+
+        let err = declaration(&path, "missing")
+            .run(&HashMap::new(), None, None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("export 'missing'"), "{}", err);
     }
 }
@@ -0,0 +1,20 @@
+# A JSON-RPC helper for the process plugin tests. `count` counts its calls,
+# `upper` uppercases the filtered value, `fail` answers with an error, `crash`
+# exits without answering and `slow` does not answer in time.
+count=0
+while IFS= read -r line; do
+  id=$(printf '%s' "$line" | sed 's/.*"id":\([0-9]*\).*/\1/')
+  case "$line" in
+    *'"method":"initialize"'*) result='{}' ;;
+    *'"method":"list"'*) result='["count","crash","fail","slow","upper"]' ;;
+    *'"method":"shutdown"'*) echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":null}"; exit 0 ;;
+    *'"name":"count"'*) count=$((count + 1)); result=$count ;;
+    *'"name":"upper"'*) result=$(printf '%s' "$line" | sed 's/.*"value":"\([^"]*\)".*/"\1"/' | tr a-z A-Z) ;;
+    *'"name":"crash"'*) exit 3 ;;
+    *'"name":"slow"'*) sleep 5; result=null ;;
+    *'"name":"fail"'*)
+      echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"error\":{\"code\":1,\"message\":\"boom\"}}"
+      continue ;;
+  esac
+  echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":$result}"
+done
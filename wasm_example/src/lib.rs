@@ -2,6 +2,48 @@ use serde::Deserialize;
 
 jintemplify_plugin::guest_plugin!();
 
+/// Host ABI this module is built against; see `guest_alloc`/`guest_dealloc`.
+#[no_mangle]
+pub extern "C" fn jintemplify_abi_version() -> u32 {
+    2
+}
+
+/// Allocates a buffer of `len` bytes for the host to write the call input into.
+#[no_mangle]
+pub extern "C" fn guest_alloc(len: u32) -> u32 {
+    let mut buf: Vec<u8> = Vec::with_capacity(len as usize);
+    let ptr = buf.as_mut_ptr();
+    std::mem::forget(buf);
+    ptr as u32
+}
+
+/// Releases a buffer previously returned by `guest_alloc`.
+#[no_mangle]
+pub extern "C" fn guest_dealloc(ptr: u32, len: u32) {
+    unsafe {
+        drop(Vec::from_raw_parts(ptr as *mut u8, 0, len as usize));
+    }
+}
+
+/// Describes the exports below so `modules:` entries can register them without
+/// listing each one in plugin.yaml.
+///
+/// Written by hand: `#[plugin_function]` and `#[plugin_filter]` do not emit
+/// it yet, so keep it in sync with the functions below.
+const METADATA: &str = r#"{"exports": [
+    {"name": "my_test_filter", "kind": "filter", "description": "Prefixes the value with var1 and var2",
+     "params": [{"name": "var1", "type": "string"}, {"name": "var2", "type": "string"}]},
+    {"name": "combine_strings", "kind": "function", "description": "Concatenates var1 and var2",
+     "params": [{"name": "var1", "type": "string"}, {"name": "var2", "type": "string"}]}
+]}"#;
+
+/// Returns a pointer to a static (ptr, len) pair describing `METADATA`.
+#[no_mangle]
+pub extern "C" fn jintemplify_metadata() -> u32 {
+    let values = Box::leak(Box::new([METADATA.as_ptr() as u32, METADATA.len() as u32]));
+    values.as_ptr() as u32
+}
+
 #[allow(dead_code)]
 #[derive(Deserialize)]
 pub struct Input {
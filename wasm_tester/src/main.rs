@@ -1,5 +1,6 @@
 // tester.rs
 use anyhow::{self};
+use jintemplify::host::HostEnv;
 use jintemplify::{FilterDeclaration, FunctionDeclaration};
 use serde::Deserialize;
 use std::str;
@@ -25,7 +26,7 @@ fn load_config() -> anyhow::Result<PluginConfig> {
 fn execute_function(config: &PluginConfig) {
     for func in config.function.iter() {
         println!("Executing function: {}", func.wasm.as_ref().unwrap().path);
-        let execute = func.create().unwrap();
+        let execute = func.create(&HostEnv::default()).unwrap();
         let args = hashmap! {"var1".to_string() => tera::Value::String("Hello".to_string()),
         "var2".to_string() => tera::Value::String(" World!".to_string()),
         };
@@ -39,7 +40,7 @@ fn execute_function(config: &PluginConfig) {
 fn execute_filter(config: &PluginConfig) {
     for filter in config.filter.iter() {
         println!("Executing filter: {}", filter.wasm.as_ref().unwrap().path);
-        let execute = filter.create().unwrap();
+        let execute = filter.create(&HostEnv::default()).unwrap();
         let args = hashmap! {"var1".to_string() => tera::Value::String("Hello".to_string()),
         "var2".to_string() => tera::Value::String(" World!".to_string()),
         };